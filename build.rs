@@ -4,4 +4,22 @@ fn main() {
         .emit_rerun_directives(true)
         .process_file("src/grammar.lalrpop")
         .unwrap();
+    // Embed the resolved versions of the main dependencies for the version command
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for (name, var) in [
+        ("candid", "CANDID_VERSION"),
+        ("candid_parser", "CANDID_PARSER_VERSION"),
+        ("ic-agent", "IC_AGENT_VERSION"),
+    ] {
+        let version = lock_version(&lock, name).unwrap_or("unknown");
+        println!("cargo:rustc-env={var}={version}");
+    }
+}
+
+fn lock_version<'a>(lock: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!("name = \"{name}\"\nversion = \"");
+    let start = lock.find(&pattern)? + pattern.len();
+    let len = lock[start..].find('"')?;
+    Some(&lock[start..start + len])
 }
@@ -11,6 +11,30 @@ assert x.map(f2) == vec {record { abc = 1 }; record { abc = 2 }};
 assert x.filter(f3) == vec { record {id=2; y=opt 5}};
 assert x.filter(f3).map(f) == vec {2};
 assert x.map(f).fold(0, f4) == 3;
+assert map(x, f) == vec {1;2};
+assert map(filter(x, f3), f) == vec {2};
+assert fold(map(x, f), 0, f4) == 3;
+let add10 = partial(f4, 10);
+assert add10(5) == 15;
+assert x.map(f).map(add10) == vec { 11; 12 };
+let add10_twice = partial(add10);
+assert add10_twice(1) == 11;
+assert fail(add10(1, 2)) ~= "add10 expects 1 arguments";
+assert fail(partial(f4, 1, 2, 3)) ~= "f4 expects 2 arguments";
+function twice(f, x) { let _ = f(f(x)) };
+assert twice(add10, 1) == 21;
+let g = f4;
+assert g(1, 2) == 3;
+assert twice(partial(f4, 2), 1) == 5;
+let not_func = 1;
+assert fail(not_func(1)) ~= "not_func is not a function";
+let fake_func = record { __func = "f4"; args = record {} };
+assert fail(fake_func(1, 2)) ~= "fake_func is not a function";
+assert type_of(add10) == "function";
+assert sum(vec { 1; (2 : nat8); (3 : int64) }) == 6;
+assert sum(vec { 1; 2.5 }) == 3.5;
+assert sum(vec {}) == 0;
+assert fail(filter(x, f)) ~= "filter function needs to return bool";
 
 let y = vec { variant { y = 1 }; variant { x = "error" }; variant { y = 2 } };
 assert y.filter(f3).map(f3_2) == vec {1;2};
@@ -23,6 +47,8 @@ function f7(acc, x) { let _ = concat(acc, vec{x[1]}) };
 assert z.filter(f6).map(f5) == record { 1; 2 = 3; 4 }; 
 assert z.filter(f6).map(f5).fold(vec{}, f7) == vec {1;3;4};
 assert z[sub(z.size(), 1)]? == 4;
+let event = record { time = 1; defer = true };
+assert event.time == 1;
 
 let s = "abcdef";
 function f8(x) { let _ = stringify(" ", x) };
@@ -50,6 +76,182 @@ assert (principal "aaaaa-aa" : service {}) == service "aaaaa-aa";
 
 assert ("this is a text" : blob) == blob "this is a text";
 assert (blob "this is a blob" : text) == "this is a blob";
+assert blob_from_hex("cafe0a") == blob "\ca\fe\n";
+assert blob_from_hex("") == blob "";
+assert as_hex(blob "\ca\fe") == "cafe";
+assert as_text(blob "hello") == "hello";
+assert principal_from_bytes(blob "\00\00\00\00\00\00\00\00\01\01") == principal "rwlgt-iiaaa-aaaaa-aaaaa-cai";
+assert principal_from_bytes(blob "") == principal "aaaaa-aa";
+assert fail(principal_from_bytes(blob "012345678901234567890123456789")) ~= "invalid principal bytes";
+assert same_principal(principal "rwlgt-iiaaa-aaaaa-aaaaa-cai", "RWLGT-IIAAA-AAAAA-AAAAA-CAI") == true;
+assert same_principal(service "aaaaa-aa", principal_from_bytes(blob "")) == true;
+assert same_principal("aaaaa-aa", "rwlgt-iiaaa-aaaaa-aaaaa-cai") == false;
+assert fail(same_principal("aaaaa-aa", 1)) ~= "expects principal or text";
+assert crc32(blob "123456789") == (3_421_780_262 : nat32);
+assert crc32(blob "") == (0 : nat32);
+let anonymous_account = account(anonymous_principal);
+assert crc32(drop(anonymous_account, 4)) == (477_776_058 : nat32);
+assert as_hex(take(anonymous_account, 4)) == "1c7a48ba";
+assert anonymous_principal == principal "2vxsx-fae";
+assert management_canister == principal "aaaaa-aa";
+assert is_anonymous(anonymous_principal) == true;
+assert is_anonymous(management_canister) == false;
+assert principal_from_seed("alice") == principal "nxu2q-em5br-fw5za-34owr-pxlfb-p6g73-6fe6i-sgggr-vtt27-hnxqk-gqe";
+assert principal_from_seed("alice") != principal_from_seed("bob");
+let sub = blob "\01\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00";
+assert verify_account_id(account(principal "aaaaa-aa"), principal "aaaaa-aa") == true;
+assert verify_account_id(account(principal "aaaaa-aa"), principal "aaaaa-aa", null) == true;
+assert verify_account_id(account(principal "aaaaa-aa"), principal "aaaaa-aa", opt sub) == false;
+assert verify_account_id(account(principal "aaaaa-aa"), principal "2vxsx-fae") == false;
+assert verify_account_id(as_hex(account(principal "aaaaa-aa")), principal "aaaaa-aa", opt blob_from_hex("0000000000000000000000000000000000000000000000000000000000000000")) == true;
+assert verify_account_id(neuron_account(principal "aaaaa-aa", 1), principal "rrkah-fqaaa-aaaaa-aaaaq-cai") == false;
+assert fail(verify_account_id(account(principal "aaaaa-aa"), principal "aaaaa-aa", blob "\01")) ~= "32 bytes";
+let ii = principal "rdmx6-jaaaa-aaaaa-aaadq-cai";
+let salt = blob_from_hex("0000000000000000000000000000000000000000000000000000000000000000");
+assert ii_principal(ii, salt, 10000, "https://example.com") == ii_principal(ii, salt, (10000 : nat64), "https://example.com");
+assert ii_principal(ii, salt, 10000, "https://example.com") != ii_principal(ii, salt, 10001, "https://example.com");
+assert ii_principal(ii, salt, 10000, "https://example.com") != ii_principal(ii, salt, 10000, "https://example.org");
+assert streaming_body(record { status_code = 200; body = blob "abc"; headers = vec {}; streaming_strategy = null }) == blob "abc";
+assert fail(streaming_body(1)) ~= "not an HTTP response";
+assert account_id_hex(principal "aaaaa-aa") == as_hex(account(principal "aaaaa-aa"));
+assert verify_account_id(account_id_hex(principal "aaaaa-aa", opt sub), principal "aaaaa-aa", sub) == true;
+assert fail(account_id_hex(principal "aaaaa-aa", blob "\01")) ~= "32 bytes";
+let p1 = random_self_auth_principal();
+assert p1 != random_self_auth_principal();
+assert type_of(p1) == "principal";
+assert next_canister_id(principal "rwlgt-iiaaa-aaaaa-aaaaa-cai") == principal "rrkah-fqaaa-aaaaa-aaaaq-cai";
+assert canister_id_range(principal "rwlgt-iiaaa-aaaaa-aaaaa-cai", 3) == vec { principal "rwlgt-iiaaa-aaaaa-aaaaa-cai"; principal "rrkah-fqaaa-aaaaa-aaaaq-cai"; principal "ryjl3-tyaaa-aaaaa-aaaba-cai" };
+assert fail(next_canister_id(principal "aaaaa-aa")) ~= "is not a canister id";
+let (t1, t2) = decode encode (1, "two");
+assert t1 == (1 : int);
+assert t2 == "two";
+let (t3) = record { 3 };
+assert t3 == 3;
+assert_count vec { 1; 2; 3 } == 3;
+assert_count blob "abc" == (3 : nat);
+assert (1_000 : nat) within (1_010, 10);
+assert (-5 : int) within (0, 5);
+assert read_csv("data.csv") == vec { record { name = "alice"; amount = 100; memo = "hello, world" }; record { name = "bob"; amount = -5; memo = "say \"hi\"" } };
+assert read_json("data.json") == record { name = "alice"; amounts = vec { 1; 2.5 }; active = true; memo = null };
+let status_args = encode_args(ic.canister_status, record { canister_id = principal "aaaaa-aa" });
+assert status_args == encode ic.canister_status(record { canister_id = principal "aaaaa-aa" });
+assert decode_args(ic.canister_status, status_args) == record { canister_id = principal "aaaaa-aa" };
+assert fail(encode_args(ic.canister_status, record { canister_id = 1 })) ~= "1 can not be of type principal";
+assert memo(1) == blob "\00\00\00\00\00\00\00\01";
+assert memo_text("hello") == blob "hello";
+assert fail(memo_text("this memo is longer than thirty two bytes")) ~= "exceeding the ledger limit";
+let t0 = now();
+sleep_until(add(t0, 100_000_000));
+assert gte(now(), add(t0, 100_000_000)) == true;
+sleep_until(0);
+assert format_number((1234567 : nat)) == "1,234,567";
+assert format_number(-123456) == "-123,456";
+assert format_number((100 : nat8)) == "100";
+assert tokens(100, 8) == (10_000_000_000 : nat);
+assert tokens(1.5, 8) == (150_000_000 : nat);
+assert tokens("0.00000001", 8) == (1 : nat);
+assert fail(tokens("0.000000001", 8)) ~= "more than 8 decimal places";
+assert display_tokens(150_000_000, 8) == "1.5";
+assert display_tokens(1, 8) == "0.00000001";
+assert display_tokens((10_000_000_000 : nat), 8) == "100";
+set_int_default nat;
+assert decode encode (record { a = 2; b = -1 }) == record { a = (2 : nat); b = (-1 : int) };
+assert type_of(42) == "nat";
+set_int_default int;
+assert decode encode (1) == (1 : int);
+
+assert sort(vec { 3; 1; 2 }) == vec { 1; 2; 3 };
+assert sort(vec { "b"; "c"; "a" }) == vec { "a"; "b"; "c" };
+assert sort(vec { (3 : nat8); 1.5; -2 }) == vec { -2; 1.5; (3 : nat8) };
+assert sort_by(vec { record { id = 2; v = "b" }; record { id = 1; v = "a" } }, "id") == vec { record { id = 1; v = "a" }; record { id = 2; v = "b" } };
+assert fail(sort(vec { 1; "a" })) ~= "cannot compare";
+assert contains(vec { principal "aaaaa-aa"; principal "2vxsx-fae" }, principal "2vxsx-fae") == true;
+assert contains(vec { record { id = 1 } }, record { id = 2 }) == false;
+assert contains(vec { (1 : nat64); (2 : nat64) }, 2) == true;
+assert contains(vec { principal "aaaaa-aa" }, service "aaaaa-aa") == true;
+assert contains("hello world", "o w") == true;
+assert starts_with("hello world", "hello") == true;
+assert ends_with("hello world", "hello") == false;
+assert replace("a-b-c", "-", "_") == "a_b_c";
+assert split("a,b,,c", ",") == vec { "a"; "b"; ""; "c" };
+assert fail(split("abc", "")) ~= "non-empty separator";
+assert to_upper("straße") == "STRASSE";
+assert to_lower("ÀB") == "àb";
+assert trim("  x y \n") == "x y";
+assert slice(vec { 1; 2; 3; 4 }, 1, 3) == vec { 2; 3 };
+assert take(vec { 1; 2; 3 }, 2) == vec { 1; 2 };
+assert drop(vec { 1; 2; 3 }, 2) == vec { 3 };
+assert take(blob "abc", 5) == blob "abc";
+assert drop(blob "abc", 5) == blob "";
+assert slice(blob "abcdef", 4, 2) == blob "";
+let __strict_bounds = true;
+assert fail(take(blob "abc", 5)) ~= "out of bounds";
+assert fail(slice(vec { 1 }, 1, 0)) ~= "out of bounds";
+assert slice(blob "abcdef", 2, 4) == blob "cd";
+let __strict_bounds = false;
+assert unique(vec { 3; 1; 3; 2; 1 }) == vec { 3; 1; 2 };
+assert unique(vec { record { id = 1; x = "a" }; record { id = 1; x = "b" }; record { id = 1; x = "a" } }) == vec { record { id = 1; x = "a" }; record { id = 1; x = "b" } };
+assert unique(vec { principal "aaaaa-aa"; principal "aaaaa-aa" }) == vec { principal "aaaaa-aa" };
+assert zip(vec { "a"; "b" }, vec { 1; 2 }) == vec { record { "a"; 1 }; record { "b"; 2 } };
+let zipped = zip(vec { "a"; "b" }, vec { 1; 2 });
+assert zipped[1][1] == 2;
+assert fail(zip(vec { 1 }, vec {})) ~= "same length";
+let pairs = enumerate(vec { "x"; "y" });
+assert pairs[1].index == (1 : nat);
+assert pairs[1].value == "y";
+assert pairs.size() == (2 : nat);
+assert format("{} owns {} tokens", principal "aaaaa-aa", (100 : nat)) == "aaaaa-aa owns 100 tokens";
+assert format("{{{}}}.{}", "x", "json") == "{x}.json";
+assert fail(format("{} and {}", 1)) ~= "more placeholders";
+assert fail(format("{}", 1, 2)) ~= "more arguments";
+assert fail(format("{x}", 1)) ~= "unmatched {";
+assert try_parse("let x = 1; assert x == 1") == variant { ok };
+let parsed = try_parse("let x = 1;\nassert x = 2");
+assert parsed.err.line == (2 : nat);
+assert parsed.err.column == (10 : nat);
+assert parsed.err.message ~= "Unexpected token";
+assert field_hash("amount") == (3_573_748_184 : nat32);
+let hashed = decode encode (record { amount = 1; memo = variant { ok } });
+assert resolve_fields(hashed, vec { "amount"; "memo"; "ok" }) == record { amount = (1 : int); memo = variant { ok } };
+assert resolve_fields(hashed, vec {}) == hashed;
+let nested = record { a = record { b = vec { record { c = opt "x" }; record { c = null } } }; t = record { 1; "two" } };
+assert select(nested, "a.b[0].c?") == "x";
+assert select(nested, "a.b") == nested.a.b;
+assert select(nested, "t.1") == "two";
+assert select(nested, "") == nested;
+assert fail(select(nested, "a.b[2].c")) ~= "select fails at a.b[2]";
+assert fail(select(nested, "a.x.c")) ~= "select fails at a.x";
+assert fail(select(nested, "a.b[x]")) ~= "not an index";
+assert assert_sorted(vec { 1; 2; 2; 5 }) == vec { 1; 2; 2; 5 };
+assert fail(assert_sorted(vec { "a"; "c"; "b" })) ~= "element 1 (\"c\") is greater than element 2 (\"b\")";
+let page = vec { record { id = 1; name = "b" }; record { id = 3; name = "a" } };
+assert assert_sorted_by(page, "id") == page;
+assert fail(assert_sorted_by(page, "name")) ~= "vec is not sorted";
+assert_type (5 : nat) : nat;
+assert_type record { a = (1 : nat); b = "x" } : record { a : nat; c : opt text };
+assert_type vec { principal "aaaaa-aa" } : vec principal;
+let base = record { name = "app"; settings = record { size = (10 : nat); mode = "fast" } };
+assert merge(base, record { settings = record { size = (20 : nat) }; extra = true }) == record { name = "app"; settings = record { size = (20 : nat); mode = "fast" }; extra = true };
+let merged = merge(base, record { settings = record { size = 30 } });
+assert merged.settings.size == 30;
+assert fail(merge(base, record { settings = record { mode = true } })) ~= "cannot override .settings.mode";
+assert fail(merge(base, 1)) ~= "merge expects two records";
+assert reverse(vec { 1; 2; 3 }) == vec { 3; 2; 1 };
+assert reverse(vec {}) == vec {};
+assert reverse(blob "ab") == blob "ba";
+assert reverse("héllo") == "olléh";
+assert fail(reverse(1)) ~= "reverse expects";
+assert service "aaaaa-aa" == principal "aaaaa-aa";
+assert record { s = service "aaaaa-aa" } == record { s = principal "aaaaa-aa" };
+assert func "aaaaa-aa".get == func "aaaaa-aa".get;
+assert func "aaaaa-aa".get != func "aaaaa-aa".put;
+assert service "aaaaa-aa" != principal "2vxsx-fae";
+assert type_of((1 : nat64)) == "nat64";
+assert add(0.1, 0.2) ~= 0.3;
+assert div(1, 3.0) ~= (0.333333333 : float32);
+let __epsilon = 0.1;
+assert 100 ~= 105.0;
+assert type_of(record { 1; "a"; opt blob "" }) == "record { int; text; opt blob }";
 
 function fac(n) {
   if eq(n, 0) {
@@ -89,6 +291,10 @@ function fib3(n) {
       let _ = add(fib3(sub(n, 1)), fib3(sub(n, 2)));
   }
 };
+function deferred(n) {
+  defer { let _ = 0; let n = 0 };
+  let _ = add(n, 1);
+};
 function __main() {
 assert fac(5) == 120;
 assert fac2(5) == 120;
@@ -96,4 +302,17 @@ assert fac3(5) == 120;
 assert fib(10) == 89;
 assert fib2(10) == 89;
 assert fib3(10) == 89;
+assert deferred(41) == 42;
+const answer = 42;
+assert answer == 42;
+let saved = 1;
+checkpoint before;
+let saved = 2;
+let scratch = 3;
+const pinned = 4;
+rollback before;
+assert saved == 1;
+assert fail(scratch) ~= "Undefined variable scratch";
+let pinned = 5;
+assert pinned == 5;
 }
@@ -1,12 +1,12 @@
 use super::error::pretty_parse;
-use super::exp::Exp;
+use super::exp::{CallMode, Exp, FuncCall};
 use super::helper::{did_to_canister_info, FileSource, MyHelper};
 use super::token::{ParserError, Tokenizer};
-use super::utils::{get_dfx_hsm_pin, resolve_path};
+use super::utils::{get_dfx_hsm_pin, load_delegation_chain, resolve_path, str_to_principal};
 use anyhow::{anyhow, Context};
-use candid::{types::value::IDLValue, Principal, TypeEnv};
+use candid::{types::value::IDLValue, types::Type, types::TypeInner, Principal, TypeEnv};
 use candid_parser::configs::Configs;
-use pretty_assertions::{assert_eq, assert_ne};
+use pretty_assertions::assert_eq;
 use std::ops::Range;
 use std::sync::Arc;
 use std::time::Instant;
@@ -16,12 +16,29 @@ pub struct Commands(pub Vec<(Command, Range<usize>)>);
 #[derive(Debug, Clone)]
 pub enum Command {
     Config(String),
+    ConfigFor(String, String),
     Show(Exp),
+    Grep(Exp, String),
     Let(String, Exp),
+    Const(String, Exp),
+    LetTuple(Vec<String>, Exp),
     Assert(BinOp, Exp, Exp),
+    AssertEventually(BinOp, Exp, Exp, std::time::Duration, std::time::Duration),
+    AssertWithin(Exp, Exp, Exp),
+    AssertCount(Exp, Exp),
+    AssertType(Exp, Type),
+    Require(Exp, Option<String>),
     Import(String, Principal, Option<String>),
     Load(Exp),
+    Use(String, bool),
+    UseCanister(Option<String>),
     Identity(String, IdentityConfig),
+    LoadIdentities(String),
+    Whoami,
+    AgentInfo,
+    Version,
+    Interface(String),
+    Record(Option<String>),
     Func {
         name: String,
         args: Vec<String>,
@@ -36,12 +53,42 @@ pub enum Command {
         then: Vec<Command>,
         else_: Vec<Command>,
     },
+    OnError(Vec<Command>),
+    Defer(Vec<Command>),
+    Time(Vec<Command>),
+    Parallel(Vec<Command>),
+    Bench(usize, Exp),
+    Watch(std::time::Duration, Exp),
+    AdvanceTime(std::time::Duration),
+    Tick(usize),
+    SetBackend(bool, String),
+    Echo(Exp),
+    Breakpoint,
+    History,
+    Replay(usize),
+    Checkpoint(String),
+    Rollback(String),
+    Export(String, Exp),
+    Log(LogLevel, Exp),
+    SetLogLevel(LogLevel),
+    SetVerbose(bool),
+    SetPretty(bool),
+    SetBlobDisplay(BlobDisplay, bool),
+    SetNumberFormat(bool),
+    SetCandidOption(String, Exp),
+    SetIntDefault(bool),
+    SetMaxResponseBytes(Option<usize>),
+    SetWallet(Exp, Option<Exp>),
+    ClearWallet,
 }
 #[derive(Debug, Clone)]
 pub enum IdentityConfig {
     Empty,
     Pem(String),
+    PemEnv(String),
+    Canister(String),
     Hsm { slot_index: usize, key_id: String },
+    Delegation { pem: String, delegation: String },
 }
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
@@ -50,6 +97,46 @@ pub enum BinOp {
     SubEqual,
     NotEqual,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobDisplay {
+    Candid,
+    Hex,
+    Text,
+    Base64,
+}
+impl std::str::FromStr for BlobDisplay {
+    type Err = String;
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "candid" => Ok(BlobDisplay::Candid),
+            "hex" => Ok(BlobDisplay::Hex),
+            "text" => Ok(BlobDisplay::Text),
+            "base64" => Ok(BlobDisplay::Base64),
+            _ => Err(format!(
+                "unknown blob display {str}, expects candid, hex, text or base64"
+            )),
+        }
+    }
+}
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(format!(
+                "unknown log level {str}, expects info, warn or error"
+            )),
+        }
+    }
+}
 
 impl Command {
     pub fn run(self, helper: &mut MyHelper) -> anyhow::Result<()> {
@@ -60,49 +147,139 @@ impl Command {
                     let info = did_to_canister_info(did, FileSource::Path(&path), None)?;
                     helper.canister_map.borrow_mut().0.insert(canister_id, info);
                 }
+                check_not_const(helper, &id)?;
                 // TODO decide if it's a Service instead
                 helper.env.0.insert(id, IDLValue::Principal(canister_id));
             }
             Command::Let(id, val) => {
+                check_not_const(helper, &id)?;
                 let is_call = val.is_call();
                 let v = val.eval(helper)?;
                 bind_value(helper, id, v, is_call, false);
             }
+            Command::Const(id, val) => {
+                check_not_const(helper, &id)?;
+                let is_call = val.is_call();
+                let v = val.eval(helper)?;
+                bind_value(helper, id.clone(), v, is_call, false);
+                helper.consts.insert(id);
+            }
+            Command::LetTuple(ids, val) => {
+                for id in ids.iter() {
+                    check_not_const(helper, id)?;
+                }
+                let is_call = val.is_call();
+                let v = val.eval(helper)?;
+                let v = if is_call {
+                    bind_call_info(helper, &ids[0], v)
+                } else {
+                    v
+                };
+                let fields = match v {
+                    IDLValue::Record(fs)
+                        if fs
+                            .iter()
+                            .enumerate()
+                            .all(|(i, f)| f.id.get_id() == i as u32) =>
+                    {
+                        fs
+                    }
+                    v => return Err(anyhow!("{v} is not a tuple")),
+                };
+                if fields.len() != ids.len() {
+                    return Err(anyhow!(
+                        "expects {} values, but the result has {} values",
+                        ids.len(),
+                        fields.len()
+                    ));
+                }
+                for (id, f) in ids.into_iter().zip(fields) {
+                    helper.env.0.insert(id, f.val);
+                }
+            }
             Command::Func { name, args, body } => {
                 helper.func_env.0.insert(name, (args, body));
             }
             Command::Assert(op, left, right) => {
                 let left = left.eval(helper)?;
                 let right = right.eval(helper)?;
-                match op {
-                    BinOp::Equal => assert_eq!(left, right),
-                    BinOp::SubEqual => {
-                        if let (IDLValue::Text(left), IDLValue::Text(right)) = (&left, &right) {
-                            assert!(left.contains(right));
-                        } else {
-                            let l_ty = left.value_ty();
-                            let r_ty = right.value_ty();
-                            let env = TypeEnv::new();
-                            if let Ok(left) = left.annotate_type(false, &env, &r_ty) {
-                                assert_eq!(left, right);
-                            } else if let Ok(right) = right.annotate_type(false, &env, &l_ty) {
-                                assert_eq!(left, right);
-                            } else {
-                                assert_eq!(left, right);
-                            }
+                assert_binop(helper, &op, left, right)?;
+            }
+            Command::AssertEventually(op, left, right, timeout, interval) => {
+                let start = Instant::now();
+                loop {
+                    let last = left
+                        .clone()
+                        .eval(helper)
+                        .and_then(|l| Ok((l, right.clone().eval(helper)?)));
+                    if let Ok((l, r)) = &last {
+                        if binop_holds(helper, &op, l, r)? {
+                            break;
                         }
                     }
-                    BinOp::NotEqual => assert_ne!(left, right),
+                    if start.elapsed() + interval > timeout {
+                        eprintln!("assertion still fails after {timeout:?}");
+                        let (left, right) = last?;
+                        assert_binop(helper, &op, left, right)?;
+                        break;
+                    }
+                    std::thread::sleep(interval);
                 }
             }
-            Command::Config(conf) => {
-                if conf.ends_with(".toml") {
-                    let path = resolve_path(&helper.base_path, &conf);
-                    let conf = std::fs::read_to_string(path)?;
-                    helper.config = conf.parse::<Configs>()?;
-                } else {
-                    helper.config = conf.parse::<Configs>()?;
+            Command::AssertWithin(v, expected, delta) => {
+                let v = v.eval(helper)?;
+                let expected = expected.eval(helper)?;
+                let delta = delta.eval(helper)?;
+                let [n, e, d] = [&v, &expected, &delta].map(crate::utils::as_int);
+                let (Some(n), Some(e), Some(d)) = (n, e, d) else {
+                    return Err(anyhow!(
+                        "assert within expects integers, found {v}, {expected} and {delta}"
+                    ));
+                };
+                assert!(
+                    n.clone() - e.clone() <= d && e - n <= d,
+                    "assertion failed: {v} is not within {delta} of {expected}"
+                );
+            }
+            Command::AssertCount(v, n) => {
+                let v = v.eval(helper)?;
+                let n = n.eval(helper)?;
+                let expected = crate::utils::as_int(&n)
+                    .ok_or_else(|| anyhow!("assert_count expects a number, found {n}"))?;
+                let len = match &v {
+                    IDLValue::Vec(vs) => vs.len(),
+                    IDLValue::Blob(b) => b.len(),
+                    _ => return Err(anyhow!("assert_count expects a vector, found {v}")),
+                };
+                assert!(
+                    expected == len,
+                    "assertion failed: expects {expected} elements, but found {len}:\n{}",
+                    crate::utils::pp_value(&v, helper)
+                );
+            }
+            Command::AssertType(v, ty) => {
+                let v = v.eval(helper)?;
+                let actual = v.value_ty();
+                assert!(
+                    v.annotate_type(true, &TypeEnv::new(), &ty).is_ok(),
+                    "assertion failed: expects type {ty}, but found {actual}:\n{}",
+                    crate::utils::pp_value(&v, helper)
+                );
+            }
+            Command::Require(cond, msg) => match cond.eval(helper)? {
+                IDLValue::Bool(true) => (),
+                IDLValue::Bool(false) => {
+                    return Err(anyhow!(
+                        msg.unwrap_or_else(|| "requirement failed".to_string())
+                    ))
                 }
+                v => return Err(anyhow!("require expects a bool condition, found {v}")),
+            },
+            Command::Config(conf) => helper.config = parse_config(helper, &conf)?,
+            Command::ConfigFor(name, conf) => {
+                str_to_principal(&name, helper)?;
+                let conf = parse_config(helper, &conf)?;
+                helper.canister_configs.insert(name, conf);
             }
             Command::Show(val) => {
                 let is_call = val.is_call();
@@ -115,8 +292,21 @@ impl Command {
                     println!("{:>width$}", format!("({duration:.2?})"), width = width);
                 }
             }
+            Command::Grep(val, pattern) => {
+                let re = regex::Regex::new(&pattern)?;
+                let is_call = val.is_call();
+                let v = val.eval(helper)?;
+                bind_value(helper, "_".to_string(), v, is_call, false);
+                let v = helper.env.0.get("_").unwrap();
+                for line in crate::utils::pp_value(v, helper).lines() {
+                    if re.is_match(line) {
+                        println!("{line}");
+                    }
+                }
+            }
             Command::Identity(id, config) => {
-                use ic_agent::identity::{BasicIdentity, Identity, Secp256k1Identity};
+                use ic_agent::identity::{BasicIdentity, Identity};
+                check_not_const(helper, &id)?;
                 let identity: Arc<dyn Identity> = match &config {
                     IdentityConfig::Hsm { slot_index, key_id } => {
                         #[cfg(target_os = "macos")]
@@ -137,16 +327,57 @@ impl Command {
                     }
                     IdentityConfig::Pem(pem_path) => {
                         let pem_path = resolve_path(&helper.base_path, pem_path);
-                        match Secp256k1Identity::from_pem_file(&pem_path) {
-                            Ok(identity) => Arc::from(identity),
-                            Err(_) => Arc::from(BasicIdentity::from_pem_file(&pem_path)?),
+                        let pem = std::fs::read(&pem_path)
+                            .with_context(|| format!("Cannot read {pem_path:?}"))?;
+                        Arc::from(pem_to_identity(&pem)?)
+                    }
+                    IdentityConfig::PemEnv(var) => {
+                        let pem = std::env::var(var)
+                            .with_context(|| format!("Environment variable {var} is not set"))?;
+                        Arc::from(
+                            pem_to_identity(pem.as_bytes())
+                                .with_context(|| format!("{var} does not contain a valid pem"))?,
+                        )
+                    }
+                    IdentityConfig::Canister(canister) => {
+                        if crate::pocketic::instance_url(&helper.agent_url).is_none() {
+                            return Err(anyhow!(
+                                "canister identity is only available on a PocketIC instance, but the replica is {}",
+                                helper.agent_url
+                            ));
                         }
+                        let canister_id = str_to_principal(canister, helper)?;
+                        Arc::from(crate::utils::CanisterIdentity(canister_id))
+                    }
+                    IdentityConfig::Delegation { pem, delegation } => {
+                        use ic_agent::identity::DelegatedIdentity;
+                        let pem_path = resolve_path(&helper.base_path, pem);
+                        let pem = std::fs::read(&pem_path)
+                            .with_context(|| format!("Cannot read {pem_path:?}"))?;
+                        let path = resolve_path(&helper.base_path, delegation);
+                        let (from_key, chain) = load_delegation_chain(&path)?;
+                        let identity =
+                            DelegatedIdentity::new(from_key, pem_to_identity(&pem)?, chain)
+                                .map_err(|e| anyhow!("{}", e))?;
+                        Arc::from(identity)
                     }
                     IdentityConfig::Empty => match helper.identity_map.0.get(&id) {
                         Some(identity) => identity.clone(),
-                        None => Arc::from(BasicIdentity::from_signing_key(
-                            ed25519_consensus::SigningKey::new(rand::thread_rng()),
-                        )),
+                        None => {
+                            let key = match &helper.identity_seed {
+                                Some(seed) => {
+                                    use sha2::{Digest, Sha256};
+                                    let bytes: [u8; 32] = Sha256::new()
+                                        .chain_update(seed)
+                                        .chain_update(&id)
+                                        .finalize()
+                                        .into();
+                                    ed25519_consensus::SigningKey::from(bytes)
+                                }
+                                None => ed25519_consensus::SigningKey::new(rand::thread_rng()),
+                            };
+                            Arc::from(BasicIdentity::from_signing_key(key))
+                        }
                     },
                 };
                 helper
@@ -160,6 +391,100 @@ impl Command {
                 helper.current_identity = id.to_string();
                 helper.env.0.insert(id, IDLValue::Principal(sender));
             }
+            Command::LoadIdentities(pattern) => {
+                use ic_agent::identity::Identity;
+                let files = crate::utils::glob_files(&helper.base_path, &pattern)?;
+                let mut count = 0;
+                for file in files {
+                    let Some(id) = file.file_stem().and_then(|s| s.to_str()) else {
+                        continue;
+                    };
+                    check_not_const(helper, id)?;
+                    let identity = match std::fs::read(&file)
+                        .with_context(|| format!("Cannot read {file:?}"))
+                        .and_then(|pem| pem_to_identity(&pem))
+                    {
+                        Ok(identity) => Arc::<dyn Identity>::from(identity),
+                        Err(e) => {
+                            log(helper, LogLevel::Warn, &format!("Skip {file:?}: {e:#}"));
+                            continue;
+                        }
+                    };
+                    let sender = identity.sender().map_err(|e| anyhow!("{}", e))?;
+                    println!("Loaded identity {id} {sender}");
+                    helper.identity_map.0.insert(id.to_string(), identity);
+                    helper
+                        .env
+                        .0
+                        .insert(id.to_string(), IDLValue::Principal(sender));
+                    count += 1;
+                }
+                if count == 0 {
+                    return Err(anyhow!("No identity is loaded from {pattern}"));
+                }
+            }
+            Command::Whoami => {
+                let sender = helper.agent.get_principal().map_err(|e| anyhow!("{}", e))?;
+                println!("Current identity {} {sender}", helper.current_identity);
+                println!("Replica {}", helper.agent_url);
+            }
+            Command::AgentInfo => {
+                let sender = helper.agent.get_principal().map_err(|e| anyhow!("{}", e))?;
+                println!("Replica {}", helper.agent_url);
+                let root_key = if helper.offline.is_some() {
+                    "not used in offline mode"
+                } else if helper.is_mainnet() {
+                    "built-in mainnet key"
+                } else {
+                    "fetched from the replica"
+                };
+                println!("Root key {root_key}");
+                // none of the agents built by ic-repl override the ingress expiry
+                let expiry = ic_agent::agent::AgentConfig::default().ingress_expiry;
+                println!("Ingress expiry {expiry:?}");
+                println!("Identity {} {sender}", helper.current_identity);
+                match &helper.wallet {
+                    Some((wallet, cycles)) => {
+                        println!("Wallet {wallet}, attaching {cycles} cycles to each update call")
+                    }
+                    None => println!("Wallet none"),
+                }
+                if let Some(canister) = &helper.default_canister {
+                    println!("Default canister {canister}");
+                }
+            }
+            Command::Record(Some(file)) => {
+                let path = resolve_path(&helper.base_path, &file);
+                std::fs::write(&path, "#!/usr/bin/env ic-repl\n")
+                    .with_context(|| format!("Cannot write {path:?}"))?;
+                eprintln!("Recording commands to {path:?}");
+                helper.recording = Some(path);
+            }
+            Command::Record(None) => {
+                let path = helper
+                    .recording
+                    .take()
+                    .ok_or_else(|| anyhow!("not recording"))?;
+                eprintln!("Recorded commands to {path:?}");
+            }
+            Command::Version => println!("{}", crate::utils::version_info()),
+            Command::Interface(name) => {
+                let id = str_to_principal(&name, helper)?;
+                let map = helper.canister_map.borrow();
+                let Some(info) = map.0.get(&id) else {
+                    return Err(anyhow!("The interface of {name} is unknown. Import it with a did file, or make a call to fetch the interface from the canister metadata"));
+                };
+                let methods = info
+                    .methods
+                    .iter()
+                    .map(|(name, func)| (name.clone(), TypeInner::Func(func.clone()).into()))
+                    .collect();
+                let actor = TypeInner::Service(methods).into();
+                println!(
+                    "{}",
+                    candid::pretty::candid::compile(&info.env, &Some(actor))
+                );
+            }
             Command::Load(e) => {
                 // TODO check for infinite loop
                 // Note that it's a bit tricky to make load as a built-in function, as it requires mutable access to helper.
@@ -177,20 +502,57 @@ impl Command {
                 if read_result.is_err() && fail_safe {
                     return Ok(());
                 }
-                let mut script = read_result.with_context(|| format!("Cannot read {path:?}"))?;
-                if script.starts_with("#!") {
-                    let line_end = script.find('\n').unwrap_or(0);
-                    script.drain(..line_end);
-                }
-                let script =
-                    shellexpand::env(&script).map_err(|e| crate::token::error2(e, 0..0))?;
-                let cmds = pretty_parse::<Commands>(file, &script)?;
+                let script = read_result.with_context(|| format!("Cannot read {path:?}"))?;
+                let (script, cmds) = parse_script(file, script)?;
+                helper.base_path = path.parent().unwrap().to_path_buf();
+                with_defer_scope(helper, |helper| {
+                    let mut prev_end = 0;
+                    for (cmd, pos) in cmds.0.into_iter() {
+                        if helper.verbose {
+                            // echo the line comments preceding the command
+                            for line in script[prev_end..pos.start].lines() {
+                                if line.trim_start().starts_with("//") {
+                                    println!("{}", line.trim());
+                                }
+                            }
+                            println!("> {}", &script[pos.clone()]);
+                        }
+                        prev_end = pos.end;
+                        run_with_source(helper, cmd, file, &script, pos)?;
+                    }
+                    Ok(())
+                })?;
+                helper.base_path = old_base;
+            }
+            Command::UseCanister(None) => {
+                helper.default_canister = None;
+            }
+            Command::UseCanister(Some(canister)) => {
+                let id = str_to_principal(&canister, helper)?;
+                println!("Default canister {canister} ({id})");
+                helper.default_canister = Some(canister);
+            }
+            Command::Use(file, ignore) => {
+                let old_base = helper.base_path.clone();
+                let path = resolve_path(&old_base, &file);
+                let script = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Cannot read {path:?}"))?;
+                let (script, cmds) = parse_script(&file, script)?;
                 helper.base_path = path.parent().unwrap().to_path_buf();
                 for (cmd, pos) in cmds.0.into_iter() {
-                    if helper.verbose {
-                        println!("> {}", &script[pos]);
+                    match cmd {
+                        Command::Func { .. } | Command::Import(..) | Command::Use(..) => {
+                            cmd.run(helper)?
+                        }
+                        _ if ignore => (),
+                        _ => {
+                            helper.base_path = old_base;
+                            return Err(anyhow!(
+                                "{file} can only contain function and import definitions, found `{}`. Use `use {file:?} ignore` to skip other commands",
+                                &script[pos]
+                            ));
+                        }
                     }
-                    cmd.run(helper)?;
                 }
                 helper.base_path = old_base;
             }
@@ -208,6 +570,322 @@ impl Command {
                     }
                 }
             }
+            Command::OnError(body) => {
+                helper.on_error = Some(body);
+            }
+            Command::Time(body) => {
+                let cost = helper.total_cost;
+                let time = Instant::now();
+                for cmd in body.into_iter() {
+                    cmd.run(helper)?;
+                }
+                let duration = time.elapsed();
+                let cost = helper.total_cost - cost;
+                if cost > 0 {
+                    eprintln!("Time: {duration:.2?}, cost: {cost} Wasm instructions");
+                } else {
+                    eprintln!("Time: {duration:.2?}");
+                }
+            }
+            Command::Parallel(body) => {
+                if helper.offline.is_some() {
+                    for cmd in body.into_iter() {
+                        cmd.run(helper)?;
+                    }
+                    return Ok(());
+                }
+                let mut ids = Vec::with_capacity(body.len());
+                let mut calls = Vec::with_capacity(body.len());
+                for cmd in body.into_iter() {
+                    match cmd {
+                        Command::Let(
+                            id,
+                            Exp::Call {
+                                method: Some(method),
+                                args,
+                                mode: CallMode::Call,
+                            },
+                        ) => {
+                            check_not_const(helper, &id)?;
+                            ids.push(id);
+                            calls.push(FuncCall {
+                                method,
+                                args: args.unwrap_or_default(),
+                            });
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "parallel block only allows `let <id> = call <canister>.<method>(<args>)`"
+                            ))
+                        }
+                    }
+                }
+                let res = crate::exp::parallel_queries(helper, calls)?;
+                for (id, v) in ids.into_iter().zip(res) {
+                    bind_value(helper, id, v, true, false);
+                }
+            }
+            Command::Bench(n, val) => {
+                const WARMUP: usize = 2;
+                let is_call = val.is_call();
+                let mut durations = Vec::with_capacity(n);
+                let mut costs = Vec::new();
+                for i in 0..WARMUP + n {
+                    let time = Instant::now();
+                    let v = val.clone().eval(helper)?;
+                    let duration = time.elapsed();
+                    if i < WARMUP {
+                        continue;
+                    }
+                    durations.push(duration);
+                    if is_call {
+                        if let (_, Some(cost)) = crate::profiling::may_extract_profiling(v) {
+                            costs.push(cost);
+                        }
+                    }
+                }
+                durations.sort();
+                let mean = durations.iter().sum::<std::time::Duration>() / n as u32;
+                println!(
+                    "{n} runs: min {:.2?}, median {:.2?}, mean {mean:.2?}, max {:.2?}",
+                    durations[0],
+                    durations[n / 2],
+                    durations[n - 1]
+                );
+                if !costs.is_empty() {
+                    costs.sort();
+                    let len = costs.len();
+                    let mean = costs.iter().sum::<i64>() / len as i64;
+                    println!(
+                        "Cost: min {}, median {}, mean {mean}, max {} Wasm instructions",
+                        costs[0],
+                        costs[len / 2],
+                        costs[len - 1]
+                    );
+                }
+            }
+            Command::Watch(interval, val) => {
+                use console::{style, Term};
+                let term = Term::stdout();
+                let mut last: Vec<String> = Vec::new();
+                let guard = WatchGuard::new();
+                loop {
+                    let now = crate::utils::now_nanos()?;
+                    let res = val.clone().eval(helper);
+                    term.clear_screen()?;
+                    println!(
+                        "Every {interval:?}, last updated at {}. Press Ctrl-C to stop.\n",
+                        crate::utils::format_timestamp(now)
+                    );
+                    match res {
+                        Ok(v) => {
+                            let (v, _) = crate::profiling::may_extract_profiling(v);
+                            let lines: Vec<_> = crate::utils::pp_value(&v, helper)
+                                .lines()
+                                .map(|l| l.to_string())
+                                .collect();
+                            for (i, line) in lines.iter().enumerate() {
+                                if !last.is_empty() && last.get(i) != Some(line) {
+                                    println!("{}", style(line).yellow().bold());
+                                } else {
+                                    println!("{line}");
+                                }
+                            }
+                            last = lines;
+                        }
+                        Err(e) => println!("{}", style(format!("Error: {e}")).red()),
+                    }
+                    let start = Instant::now();
+                    while start.elapsed() < interval {
+                        if guard.interrupted() {
+                            return Ok(());
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                    }
+                }
+            }
+            Command::AdvanceTime(duration) => {
+                let now = crate::pocketic::advance_time(&helper.agent_url, duration)?;
+                if helper.verbose {
+                    println!("Time advanced to {}", crate::utils::format_timestamp(now));
+                }
+            }
+            Command::Tick(n) => crate::pocketic::tick(&helper.agent_url, n)?,
+            Command::SetBackend(pocketic, url) => {
+                if helper.offline.is_some() {
+                    return Err(anyhow!("set_backend is not available in offline mode"));
+                }
+                let url = if pocketic {
+                    format!(
+                        "{}/",
+                        crate::pocketic::require_instance(&url, "set_backend")?
+                    )
+                } else {
+                    match url.as_str() {
+                        "local" => "http://localhost:4943/".to_string(),
+                        "ic" => "https://icp0.io/".to_string(),
+                        _ => url,
+                    }
+                };
+                let identity = helper
+                    .identity_map
+                    .0
+                    .get(&helper.current_identity)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("identity {} not found", helper.current_identity))?;
+                helper.agent = ic_agent::Agent::builder()
+                    .with_url(url.clone())
+                    .with_arc_identity(identity)
+                    .with_max_tcp_error_retries(2)
+                    .with_max_polling_time(std::time::Duration::from_secs(60 * 10))
+                    .build()?;
+                helper.agent_url = url;
+                helper.fetch_root_key_if_needed()?;
+                if helper.verbose {
+                    println!("Replica {}", helper.agent_url);
+                }
+            }
+            Command::Breakpoint => {
+                use rustyline::error::ReadlineError;
+                eprintln!("Paused at breakpoint. Type `continue` to resume the script, or `abort` to stop it.");
+                let verbose = helper.verbose;
+                helper.verbose = true;
+                let mut rl = rustyline::DefaultEditor::new()?;
+                let res = loop {
+                    let p = format!("{}@breakpoint> ", helper.current_identity);
+                    match rl.readline(&p) {
+                        Ok(line) => {
+                            let _ = rl.add_history_entry(&line);
+                            match line.trim().trim_end_matches(';') {
+                                "" => (),
+                                "continue" => break Ok(()),
+                                "abort" => break Err(anyhow!("Script aborted at breakpoint")),
+                                line => match pretty_parse::<Command>("stdin", line) {
+                                    Ok(cmd) => {
+                                        if let Err(e) = cmd.run(helper) {
+                                            eprintln!("Error: {e:?}");
+                                        }
+                                    }
+                                    Err(e) => eprintln!("Error: {e:?}"),
+                                },
+                            }
+                        }
+                        Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                            break Err(anyhow!("Script aborted at breakpoint"))
+                        }
+                        Err(e) => break Err(e.into()),
+                    }
+                };
+                helper.verbose = verbose;
+                res?;
+            }
+            Command::History => {
+                for (i, line) in helper.history.iter().enumerate() {
+                    println!("{:>4}  {}", i + 1, line.replace('\n', "\n      "));
+                }
+            }
+            Command::Replay(n) => {
+                let line = helper
+                    .history
+                    .get(n - 1)
+                    .ok_or_else(|| anyhow!("history has only {} entries", helper.history.len()))?
+                    .clone();
+                println!("> {line}");
+                let cmd = pretty_parse::<Command>("history", &line)?;
+                cmd.run(helper)?;
+            }
+            Command::Checkpoint(name) => {
+                helper
+                    .checkpoints
+                    .insert(name, (helper.env.clone(), helper.consts.clone()));
+            }
+            Command::Rollback(name) => {
+                let (env, consts) = helper
+                    .checkpoints
+                    .get(&name)
+                    .ok_or_else(|| anyhow!("checkpoint {name} not found"))?
+                    .clone();
+                helper.env = env;
+                helper.consts = consts;
+            }
+            Command::Export(name, val) => {
+                let v = match val.eval(helper)? {
+                    v @ (IDLValue::Opt(_)
+                    | IDLValue::Vec(_)
+                    | IDLValue::Blob(_)
+                    | IDLValue::Record(_)
+                    | IDLValue::Variant(_)) => v.to_string(),
+                    v => crate::utils::stringify(&v)?.into_owned(),
+                };
+                // single-quote the value, so that the line can be sourced by a POSIX shell
+                let line = format!("{name}='{}'\n", v.replace('\'', "'\\''"));
+                match &helper.export_file {
+                    Some(path) => {
+                        use std::io::Write;
+                        let mut file = std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .with_context(|| format!("Cannot open {path:?}"))?;
+                        file.write_all(line.as_bytes())?;
+                    }
+                    None => print!("{line}"),
+                }
+            }
+            Command::Echo(msg) => match msg.eval(helper)? {
+                IDLValue::Text(msg) => println!("{msg}"),
+                v => println!("{v}"),
+            },
+            Command::Log(level, msg) => {
+                let msg = match msg.eval(helper)? {
+                    IDLValue::Text(msg) => msg,
+                    v => v.to_string(),
+                };
+                log(helper, level, &msg);
+            }
+            Command::SetLogLevel(level) => helper.log_level = level,
+            Command::SetVerbose(verbose) => helper.verbose = verbose,
+            Command::SetPretty(pretty) => helper.pretty = pretty,
+            Command::SetBlobDisplay(mode, full) => {
+                helper.blob_display = mode;
+                helper.blob_full = full;
+            }
+            Command::SetNumberFormat(grouped) => helper.number_grouped = grouped,
+            Command::SetCandidOption(key, val) => match (key.as_str(), val.eval(helper)?) {
+                ("labels", IDLValue::Vec(names)) => {
+                    let mut labels = std::collections::BTreeMap::new();
+                    for name in names {
+                        let IDLValue::Text(name) = name else {
+                            return Err(anyhow!("labels expects a vector of text, found {name}"));
+                        };
+                        labels.insert(candid::idl_hash(&name), name);
+                    }
+                    helper.labels = labels;
+                }
+                (key, v) => return Err(anyhow!("unexpected value {v} for candid option {key}")),
+            },
+            Command::SetIntDefault(nat) => helper.nat_default = nat,
+            Command::SetWallet(wallet, cycles) => {
+                let wallet = match wallet.eval(helper)? {
+                    IDLValue::Principal(id) | IDLValue::Service(id) => id,
+                    IDLValue::Text(name) => str_to_principal(&name, helper)?,
+                    v => return Err(anyhow!("{v} is not a principal")),
+                };
+                let cycles = match cycles.map(|e| e.eval(helper)).transpose()? {
+                    None => 0,
+                    Some(IDLValue::Number(n)) => n.replace('_', "").parse::<u128>()?,
+                    Some(IDLValue::Nat(n)) => n.0.to_string().parse::<u128>()?,
+                    Some(IDLValue::Nat64(n)) => n as u128,
+                    Some(v) => return Err(anyhow!("{v} is not a cycle amount")),
+                };
+                helper.wallet = Some((wallet, cycles));
+            }
+            Command::ClearWallet => helper.wallet = None,
+            Command::SetMaxResponseBytes(n) => helper.max_response_bytes = n,
+            Command::Defer(body) => {
+                let env = helper.env.clone();
+                helper.defers.push((body, env));
+            }
             Command::While { cond, body } => loop {
                 let IDLValue::Bool(cond) = cond.clone().eval(helper)? else {
                     return Err(anyhow!("while condition is not a boolean expression"));
@@ -224,6 +902,121 @@ impl Command {
     }
 }
 
+impl Command {
+    /// Run the command. If the command fails, run the on_error handler before propagating the error.
+    /// Assertion failures are caught as well, so that the handler can clean up before the script aborts.
+    pub fn run_or_trap(self, helper: &mut MyHelper) -> anyhow::Result<()> {
+        use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+        if helper.on_error.is_none() {
+            return self.run(helper);
+        }
+        let res = catch_unwind(AssertUnwindSafe(|| self.run(helper)));
+        if !matches!(res, Ok(Ok(()))) {
+            run_on_error(helper);
+        }
+        match res {
+            Ok(res) => res,
+            Err(panic) => resume_unwind(panic),
+        }
+    }
+}
+
+/// Run a command loaded from a script. When an assertion fails, print the location and source text of
+/// the command in the script, as the panic message only shows the values.
+fn run_with_source(
+    helper: &mut MyHelper,
+    cmd: Command,
+    file: &str,
+    script: &str,
+    pos: Range<usize>,
+) -> anyhow::Result<()> {
+    use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+    match catch_unwind(AssertUnwindSafe(|| cmd.run_or_trap(helper))) {
+        Ok(res) => res,
+        Err(panic) => {
+            let line = script[..pos.start].matches('\n').count() + 1;
+            eprintln!(
+                "{} {file}:{line}\n  {}",
+                console::style("Assertion failed at").red().bold(),
+                script[pos].replace('\n', "\n  ")
+            );
+            resume_unwind(panic)
+        }
+    }
+}
+
+/// Run the most recently declared on_error handler once. Errors in the handler are reported, but do not
+/// replace the original error.
+pub fn run_on_error(helper: &mut MyHelper) {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    let Some(handler) = helper.on_error.take() else {
+        return;
+    };
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        for cmd in handler.into_iter() {
+            cmd.run(helper)?;
+        }
+        Ok::<(), anyhow::Error>(())
+    }));
+    match res {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => eprintln!("Error in on_error handler: {e:?}"),
+        Err(_) => eprintln!("Error in on_error handler: assertion failed"),
+    }
+}
+
+/// Run `f` as a new scope. Blocks deferred within the scope run in reverse order when it exits,
+/// whether it succeeds, returns an error or fails an assertion.
+pub fn with_defer_scope<T>(
+    helper: &mut MyHelper,
+    f: impl FnOnce(&mut MyHelper) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+    let depth = helper.defers.len();
+    let res = catch_unwind(AssertUnwindSafe(|| f(helper)));
+    let deferred = run_defers(helper, depth);
+    match res {
+        Ok(res) => {
+            let v = res?;
+            deferred?;
+            Ok(v)
+        }
+        Err(panic) => resume_unwind(panic),
+    }
+}
+
+/// Pop and run the deferred blocks above `depth`, each with the variables visible when it was deferred.
+/// All blocks run even if one of them fails; the first failure is reported.
+pub fn run_defers(helper: &mut MyHelper, depth: usize) -> anyhow::Result<()> {
+    use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+    let mut error = None;
+    let mut panic = None;
+    while helper.defers.len() > depth {
+        let (body, env) = helper.defers.pop().unwrap();
+        let old_env = std::mem::replace(&mut helper.env, env);
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            for cmd in body.into_iter() {
+                cmd.run(helper)?;
+            }
+            Ok::<(), anyhow::Error>(())
+        }));
+        helper.env = old_env;
+        match res {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                error.get_or_insert(e);
+            }
+            Err(p) => {
+                panic.get_or_insert(p);
+            }
+        }
+    }
+    if let Some(p) = panic {
+        resume_unwind(p);
+    }
+    error.map_or(Ok(()), Err)
+}
+
 impl std::str::FromStr for Command {
     type Err = ParserError;
     fn from_str(str: &str) -> Result<Self, Self::Err> {
@@ -239,22 +1032,255 @@ impl std::str::FromStr for Commands {
     }
 }
 
+/// Append a successfully executed command to the recorded script. Pem file paths are replaced by
+/// environment variables, so that the recorded script does not depend on local key files.
+pub fn record_command(helper: &MyHelper, cmd: &Command, line: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+    let Some(path) = &helper.recording else {
+        return Ok(());
+    };
+    let line = match cmd {
+        Command::Record(_) | Command::History | Command::Replay(_) => return Ok(()),
+        Command::Identity(id, IdentityConfig::Pem(_)) => {
+            format!("identity {id} \"${{{}_PEM}}\"", id.to_uppercase())
+        }
+        _ => line.trim().trim_end_matches(';').to_string(),
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Cannot write {path:?}"))?;
+    writeln!(file, "{line};")?;
+    Ok(())
+}
+
+/// Strip the shebang line, expand environment variables and parse the script.
+fn parse_script(file: &str, mut script: String) -> anyhow::Result<(String, Commands)> {
+    if script.starts_with("#!") {
+        let line_end = script.find('\n').unwrap_or(0);
+        script.drain(..line_end);
+    }
+    let script = shellexpand::env(&script)
+        .map_err(|e| crate::token::error2(e, 0..0))?
+        .into_owned();
+    let cmds = pretty_parse::<Commands>(file, &script)?;
+    Ok((script, cmds))
+}
+
+fn pem_to_identity(pem: &[u8]) -> anyhow::Result<Box<dyn ic_agent::Identity>> {
+    use ic_agent::identity::{BasicIdentity, Secp256k1Identity};
+    Ok(match Secp256k1Identity::from_pem(pem) {
+        Ok(identity) => Box::new(identity),
+        Err(_) => Box::new(BasicIdentity::from_pem(pem)?),
+    })
+}
+
+/// Print a log message if `level` is at least the current log level. Info goes to stdout,
+/// so that it can be piped along with the results; warnings and errors go to stderr.
+pub fn log(helper: &MyHelper, level: LogLevel, msg: &str) {
+    if level < helper.log_level || (level == LogLevel::Info && !helper.verbose) {
+        return;
+    }
+    match level {
+        LogLevel::Info => println!("{} {msg}", console::style("[info]").cyan()),
+        LogLevel::Warn => eprintln!("{} {msg}", console::style("[warn]").yellow()),
+        LogLevel::Error => eprintln!("{} {msg}", console::style("[error]").red().bold()),
+    }
+}
+
+static WATCHING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+static INTERRUPTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+/// Catches Ctrl-C while a `watch` command is running. The Ctrl-C handler is installed on first use,
+/// and exits the process as usual when no `watch` command is running.
+pub(crate) struct WatchGuard;
+impl WatchGuard {
+    pub(crate) fn new() -> Self {
+        use std::sync::atomic::Ordering;
+        static HANDLER: std::sync::Once = std::sync::Once::new();
+        HANDLER.call_once(|| {
+            std::thread::spawn(|| {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                loop {
+                    rt.block_on(tokio::signal::ctrl_c()).unwrap();
+                    if WATCHING.load(Ordering::SeqCst) {
+                        INTERRUPTED.store(true, Ordering::SeqCst);
+                    } else {
+                        std::process::exit(130);
+                    }
+                }
+            });
+        });
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        WATCHING.store(true, Ordering::SeqCst);
+        WatchGuard
+    }
+    pub(crate) fn interrupted(&self) -> bool {
+        INTERRUPTED.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        WATCHING.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+}
 fn bind_value(helper: &mut MyHelper, id: String, v: IDLValue, is_call: bool, display: bool) {
     if display {
         if helper.verbose {
-            println!("{v}");
+            let s = crate::utils::pp_value(&v, helper);
+            if helper.interactive {
+                print_paged(&s);
+            } else {
+                println!("{s}");
+            }
         } else if let IDLValue::Text(v) = &v {
             println!("{v}");
         }
     }
-    if is_call {
-        let (v, cost) = crate::profiling::may_extract_profiling(v);
-        if let Some(cost) = cost {
-            let cost_id = format!("__cost_{id}");
-            helper.env.0.insert(cost_id, IDLValue::Int64(cost));
+    let v = if is_call {
+        bind_call_info(helper, &id, v)
+    } else {
+        v
+    };
+    helper.env.0.insert(id, v);
+}
+fn epsilon(helper: &MyHelper) -> anyhow::Result<f64> {
+    Ok(match helper.env.0.get("__epsilon") {
+        Some(v) => {
+            match crate::utils::cast_type(v.clone(), &candid::types::TypeInner::Float64.into())? {
+                IDLValue::Float64(e) => e,
+                _ => unreachable!(),
+            }
+        }
+        None => 1e-6,
+    })
+}
+fn assert_binop(
+    helper: &MyHelper,
+    op: &BinOp,
+    left: IDLValue,
+    right: IDLValue,
+) -> anyhow::Result<()> {
+    if binop_holds(helper, op, &left, &right)? {
+        return Ok(());
+    }
+    match op {
+        BinOp::Equal => assert_eq!(left, right),
+        BinOp::SubEqual => match (&left, &right) {
+            (IDLValue::Float32(_) | IDLValue::Float64(_), _)
+            | (_, IDLValue::Float32(_) | IDLValue::Float64(_)) => panic!(
+                "assertion failed: {left} ~= {right} (epsilon {})",
+                epsilon(helper)?
+            ),
+            _ => panic!("assertion failed: {left} ~= {right}"),
+        },
+        BinOp::NotEqual => panic!("assertion failed: {left} != {right}"),
+    }
+    Ok(())
+}
+fn binop_holds(
+    helper: &MyHelper,
+    op: &BinOp,
+    left: &IDLValue,
+    right: &IDLValue,
+) -> anyhow::Result<bool> {
+    Ok(match op {
+        BinOp::Equal => crate::utils::ref_eq(left, right) || left == right,
+        BinOp::SubEqual => {
+            if let (IDLValue::Text(left), IDLValue::Text(right)) = (left, right) {
+                left.contains(right.as_str())
+            } else if let Some(eq) = crate::utils::approx_eq(left, right, epsilon(helper)?)? {
+                eq
+            } else {
+                let env = TypeEnv::new();
+                if let Ok(l) = left.annotate_type(false, &env, &right.value_ty()) {
+                    l == *right
+                } else if let Ok(r) = right.annotate_type(false, &env, &left.value_ty()) {
+                    *left == r
+                } else {
+                    left == right
+                }
+            }
         }
-        helper.env.0.insert(id, v);
+        BinOp::NotEqual => !crate::utils::ref_eq(left, right),
+    })
+}
+/// Parse the config in TOML format, or read it from a `.toml` file.
+fn parse_config(helper: &MyHelper, conf: &str) -> anyhow::Result<Configs> {
+    if conf.ends_with(".toml") {
+        let path = resolve_path(&helper.base_path, conf);
+        let conf =
+            std::fs::read_to_string(&path).with_context(|| format!("Cannot read {path:?}"))?;
+        Ok(conf.parse::<Configs>()?)
     } else {
-        helper.env.0.insert(id, v);
+        Ok(conf.parse::<Configs>()?)
+    }
+}
+fn check_not_const(helper: &MyHelper, id: &str) -> anyhow::Result<()> {
+    if helper.consts.contains(id) {
+        return Err(anyhow!("cannot rebind constant {id}"));
+    }
+    Ok(())
+}
+/// Print the text one screen at a time when it doesn't fit in the terminal.
+/// Space shows the next page, Enter the next line, and q stops the output.
+fn print_paged(s: &str) {
+    use console::{style, Key, Term};
+    let term = Term::stdout();
+    let lines: Vec<_> = s.lines().collect();
+    let height = term.size().0 as usize;
+    if !term.is_term() || lines.len() < height {
+        println!("{s}");
+        return;
+    }
+    let page = height.saturating_sub(1).max(1);
+    let mut shown = 0;
+    let mut end = page;
+    loop {
+        for line in &lines[shown..end] {
+            println!("{line}");
+        }
+        shown = end;
+        if shown == lines.len() {
+            return;
+        }
+        let prompt = format!(
+            "-- {shown}/{} lines. Space: next page, Enter: next line, q: quit --",
+            lines.len()
+        );
+        let _ = term.write_str(&format!("{}", style(prompt).reverse()));
+        let key = term.read_key();
+        let _ = term.clear_line();
+        end = match key {
+            Ok(Key::Char(' ') | Key::PageDown) => shown + page,
+            Ok(Key::Enter | Key::ArrowDown) => shown + 1,
+            Ok(Key::Char('q') | Key::Escape) | Err(_) => return,
+            Ok(_) => shown,
+        }
+        .min(lines.len());
+    }
+}
+/// Bind the request id, duration and cost of the last call, and return the call result without the cost.
+fn bind_call_info(helper: &mut MyHelper, id: &str, v: IDLValue) -> IDLValue {
+    if let Some(request_id) = helper.last_request_id.take() {
+        helper
+            .env
+            .0
+            .insert("__request_id".to_string(), IDLValue::Text(request_id));
+    }
+    if let Some(duration) = helper.last_call_duration.take() {
+        helper.env.0.insert(
+            "__call_duration".to_string(),
+            IDLValue::Nat64(duration.as_millis() as u64),
+        );
+    }
+    let (v, cost) = crate::profiling::may_extract_profiling(v);
+    if let Some(cost) = cost {
+        let cost_id = format!("__cost_{id}");
+        helper.env.0.insert(cost_id, IDLValue::Int64(cost));
+        helper.total_cost += cost;
     }
+    v
 }
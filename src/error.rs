@@ -42,6 +42,36 @@ fn report_expected(expected: &[String]) -> Vec<String> {
     vec![doc.pretty(70).to_string()]
 }
 
+/// The span and message of a parser error, without the source text.
+pub fn error_message(e: &ParserError) -> (std::ops::Range<usize>, String) {
+    use lalrpop_util::ParseError::*;
+    let (span, msg, expected) = match e {
+        User { error } => (error.span.clone(), error.err.clone(), None),
+        InvalidToken { location } => (*location..location + 1, "Invalid token".to_string(), None),
+        UnrecognizedEof { location, expected } => (
+            *location..location + 1,
+            "Unexpected EOF".to_string(),
+            Some(expected),
+        ),
+        UnrecognizedToken { token, expected } => (
+            token.0..token.2,
+            "Unexpected token".to_string(),
+            Some(expected),
+        ),
+        ExtraToken { token } => (token.0..token.2, "Extra token".to_string(), None),
+    };
+    let notes = expected
+        .map(|e| report_expected(e))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|note| note.replace('\n', " "));
+    let msg = std::iter::once(msg)
+        .chain(notes)
+        .collect::<Vec<_>>()
+        .join(". ");
+    (span, msg)
+}
+
 pub fn pretty_parse<T>(name: &str, str: &str) -> Result<T, ParserError>
 where
     T: std::str::FromStr<Err = ParserError>,
@@ -3,16 +3,17 @@ use super::helper::{find_init_args, MyHelper, OfflineOutput};
 use super::selector::{project, Selector};
 use super::token::{ParserError, Tokenizer};
 use super::utils::{
-    args_to_value, as_u32, cast_type, get_effective_canister_id, get_field, resolve_path,
-    str_to_principal,
+    args_to_value, arith, as_u32, cast_type, get_effective_canister_id, get_field, resolve_path,
+    sort_by_key, str_to_principal,
 };
 use anyhow::{anyhow, Context, Result};
 use candid::{
     types::value::{IDLArgs, IDLField, IDLValue, VariantValue},
     types::{Function, Label, Type, TypeInner},
     utils::check_unique,
-    Principal, TypeEnv,
+    Decode, Encode, Principal, TypeEnv,
 };
+use candid_parser::types::IDLType;
 use futures::future::try_join_all;
 use std::collections::BTreeMap;
 
@@ -57,8 +58,13 @@ pub struct Method {
 #[derive(Debug, Clone)]
 pub enum CallMode {
     Call,
+    CallAs(Vec<IDLType>),
     Encode,
     Proxy(String),
+    // return the reply bytes without decoding
+    RawReply,
+    // method name is given by an expression evaluated at runtime
+    Dynamic(Box<Exp>),
 }
 #[derive(Debug, Clone)]
 pub struct FuncCall {
@@ -76,7 +82,10 @@ impl Exp {
         matches!(
             self,
             Exp::Call {
-                mode: CallMode::Call,
+                mode: CallMode::Call
+                    | CallMode::CallAs(_)
+                    | CallMode::Dynamic(_)
+                    | CallMode::RawReply,
                 ..
             }
         )
@@ -84,12 +93,19 @@ impl Exp {
     pub fn eval(self, helper: &MyHelper) -> Result<IDLValue> {
         Ok(match self {
             Exp::Path(id, path) => {
-                let v = helper
-                    .env
-                    .0
-                    .get(&id)
-                    .ok_or_else(|| anyhow!("Undefined variable {}", id))?
-                    .clone();
+                let v = match helper.env.0.get(&id) {
+                    Some(v) => v.clone(),
+                    None if helper.func_env.0.contains_key(&id) => {
+                        func_value(helper, id, Vec::new())
+                    }
+                    None if id == "anonymous_principal" => {
+                        IDLValue::Principal(Principal::anonymous())
+                    }
+                    None if id == "management_canister" => {
+                        IDLValue::Principal(Principal::management_canister())
+                    }
+                    None => return Err(anyhow!("Undefined variable {}", id)),
+                };
                 project(helper, v, path)?
             }
             Exp::AnnVal(v, ty) => {
@@ -103,6 +119,15 @@ impl Exp {
             Exp::Apply(func, exps) => {
                 use crate::account_identifier::*;
 
+                // user-defined functions take precedence over built-in functions
+                if helper.func_env.0.contains_key(&func) {
+                    let mut args = Vec::new();
+                    for e in exps.into_iter() {
+                        args.push(e.eval(helper)?);
+                    }
+                    return apply_func(helper, &func, args);
+                }
+
                 // functions that cannot evaluate arguments first
                 match func.as_str() {
                     "ite" => {
@@ -130,6 +155,139 @@ impl Exp {
                             Err(_) => IDLValue::Bool(false),
                         });
                     }
+                    "decode_reject" => {
+                        use ic_agent::AgentError;
+                        if exps.len() != 1 {
+                            return Err(anyhow!("decode_reject expects an expression"));
+                        }
+                        let e = match exps[0].clone().eval(helper) {
+                            Ok(_) => return Err(anyhow!("Expects a rejected call")),
+                            Err(e) => e,
+                        };
+                        let reject = match e.downcast_ref::<AgentError>() {
+                            Some(
+                                AgentError::CertifiedReject(reject)
+                                | AgentError::UncertifiedReject(reject),
+                            ) => reject,
+                            _ => return Err(e),
+                        };
+                        let mut fields = vec![
+                            IDLField {
+                                id: Label::Named("code".to_string()),
+                                val: IDLValue::Text(format!("{:?}", reject.reject_code)),
+                            },
+                            IDLField {
+                                id: Label::Named("message".to_string()),
+                                val: IDLValue::Text(reject.reject_message.clone()),
+                            },
+                            IDLField {
+                                id: Label::Named("error_code".to_string()),
+                                val: match &reject.error_code {
+                                    Some(code) => {
+                                        IDLValue::Opt(Box::new(IDLValue::Text(code.clone())))
+                                    }
+                                    None => IDLValue::None,
+                                },
+                            },
+                        ];
+                        fields.sort_unstable_by_key(|f| f.id.get_id());
+                        return Ok(IDLValue::Record(fields));
+                    }
+                    "fold" => {
+                        let [vec, init, Exp::Path(f, path)] = exps.as_slice() else {
+                            return Err(anyhow!("fold expects (vec, init, function name)"));
+                        };
+                        if !path.is_empty() {
+                            return Err(anyhow!("fold expects (vec, init, function name)"));
+                        }
+                        let selector = Selector::Fold(init.clone(), f.to_string());
+                        return project(helper, vec.clone().eval(helper)?, vec![selector]);
+                    }
+                    "encode_args" | "decode_args" => {
+                        let [Exp::Path(canister, path), v] = exps.as_slice() else {
+                            return Err(anyhow!("{func} expects (canister.method, value)"));
+                        };
+                        let [Selector::Field(method)] = path.as_slice() else {
+                            return Err(anyhow!("{func} expects (canister.method, value)"));
+                        };
+                        let method = Method {
+                            canister: canister.to_string(),
+                            method: method.to_string(),
+                        };
+                        let Some((env, f)) = method.get_info(helper, false)?.signature else {
+                            return Err(anyhow!(
+                                "cannot get the signature of {}.{}",
+                                method.canister,
+                                method.method
+                            ));
+                        };
+                        let v = v.clone().eval(helper)?;
+                        return Ok(if func == "encode_args" {
+                            let args = match (f.args.len(), v) {
+                                (0, IDLValue::Null) => Vec::new(),
+                                (1, v) => vec![v],
+                                (n, IDLValue::Record(fs))
+                                    if fs.len() == n
+                                        && fs
+                                            .iter()
+                                            .enumerate()
+                                            .all(|(i, f)| f.id.get_id() == i as u32) =>
+                                {
+                                    fs.into_iter().map(|f| f.val).collect()
+                                }
+                                (n, v) => {
+                                    return Err(anyhow!(
+                                        "{}.{} expects {n} arguments, but {v} is not a tuple of {n} values",
+                                        method.canister,
+                                        method.method
+                                    ))
+                                }
+                            };
+                            IDLValue::Blob(IDLArgs::new(&args).to_bytes_with_types(&env, &f.args)?)
+                        } else {
+                            let IDLValue::Blob(bytes) = v else {
+                                return Err(anyhow!("decode_args expects a blob"));
+                            };
+                            args_to_value(IDLArgs::from_bytes_with_types(&bytes, &env, &f.args)?)
+                        });
+                    }
+                    "partial" => {
+                        let Some((func, bound)) = exps.split_first() else {
+                            return Err(anyhow!("partial expects (function, args, ...)"));
+                        };
+                        let func = func.clone().eval(helper)?;
+                        let (f, mut args) = as_func(helper, &func)
+                            .ok_or_else(|| anyhow!("{func} is not a function"))?;
+                        let Some((formal_args, _)) = helper.func_env.0.get(&f) else {
+                            return Err(anyhow!("Unknown function {}", f));
+                        };
+                        if args.len() + bound.len() > formal_args.len() {
+                            return Err(anyhow!(
+                                "{} expects {} arguments, but {} is provided",
+                                f,
+                                formal_args.len(),
+                                args.len() + bound.len()
+                            ));
+                        }
+                        for arg in bound {
+                            args.push(arg.clone().eval(helper)?);
+                        }
+                        return Ok(func_value(helper, f, args));
+                    }
+                    "map" | "filter" => {
+                        let [vec, Exp::Path(f, path)] = exps.as_slice() else {
+                            return Err(anyhow!("{func} expects (vec, function name)"));
+                        };
+                        if !path.is_empty() {
+                            return Err(anyhow!("{func} expects (vec, function name)"));
+                        }
+                        let selector = match func.as_str() {
+                            "map" => Selector::Map(f.to_string()),
+                            "filter" => Selector::Filter(f.to_string()),
+                            _ => unreachable!(),
+                        };
+                        return project(helper, vec.clone().eval(helper)?, vec![selector]);
+                    }
                     "export" => {
                         use std::io::{BufWriter, Write};
                         if exps.len() <= 1 {
@@ -166,6 +324,45 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("account expects principal")),
                     },
+                    "verify_account_id" => {
+                        let (account, principal, subaccount) = match args.as_slice() {
+                            [account, IDLValue::Principal(principal)] => (account, principal, None),
+                            [account, IDLValue::Principal(principal), subaccount] => {
+                                (account, principal, Some(subaccount))
+                            }
+                            _ => {
+                                return Err(anyhow!(
+                                    "verify_account_id expects (account, principal, opt subaccount)"
+                                ))
+                            }
+                        };
+                        let account = match account {
+                            IDLValue::Blob(b) => AccountIdentifier::from_slice(b),
+                            IDLValue::Text(hex) => AccountIdentifier::from_hex(hex),
+                            _ => return Err(anyhow!("{account} is not an account id")),
+                        }
+                        .map_err(|e| anyhow!("{e}"))?;
+                        let subaccount = as_subaccount(subaccount)?;
+                        IDLValue::Bool(AccountIdentifier::new(*principal, subaccount) == account)
+                    }
+                    "account_id_hex" => match args.as_slice() {
+                        [IDLValue::Principal(principal)] => {
+                            IDLValue::Text(AccountIdentifier::new(*principal, None).to_hex())
+                        }
+                        [IDLValue::Principal(principal), subaccount] => IDLValue::Text(
+                            AccountIdentifier::new(*principal, as_subaccount(Some(subaccount))?)
+                                .to_hex(),
+                        ),
+                        _ => {
+                            return Err(anyhow!(
+                                "account_id_hex expects (principal, opt subaccount)"
+                            ))
+                        }
+                    },
+                    "crc32" => match args.as_slice() {
+                        [IDLValue::Blob(bytes)] => IDLValue::Nat32(crc32fast::hash(bytes)),
+                        _ => return Err(anyhow!("crc32 expects a blob")),
+                    },
                     "neuron_account" => match args.as_slice() {
                         [IDLValue::Principal(principal), nonce] => {
                             let nonce = match nonce {
@@ -207,6 +404,318 @@ impl Exp {
                             }
                         }
                     }
+                    "metadata" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id), IDLValue::Text(name)] => {
+                            crate::utils::fetch_metadata_section(&helper.agent, *id, name)?
+                        }
+                        _ => return Err(anyhow!("metadata expects (canister_id, section_name)")),
+                    },
+                    "subnet_of" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id)] => {
+                            match crate::utils::fetch_subnet_id(
+                                &helper.agent,
+                                *id,
+                                helper.is_mainnet(),
+                            ) {
+                                Ok(Some(subnet)) => IDLValue::Principal(subnet),
+                                Ok(None) => IDLValue::Text("unknown".to_string()),
+                                Err(e) => {
+                                    eprintln!("Cannot find the subnet of {id}: {e}");
+                                    IDLValue::Text("unknown".to_string())
+                                }
+                            }
+                        }
+                        _ => return Err(anyhow!("subnet_of expects a canister id")),
+                    },
+                    "assert_subnet" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id), IDLValue::Principal(expected)] => {
+                            let subnet = crate::utils::fetch_subnet_id(
+                                &helper.agent,
+                                *id,
+                                helper.is_mainnet(),
+                            )
+                            .with_context(|| format!("Cannot find the subnet of {id}"))?
+                            .ok_or_else(|| {
+                                anyhow!("Cannot find the subnet of {id}, the replica does not provide this information")
+                            })?;
+                            if subnet != *expected {
+                                return Err(anyhow!(
+                                    "canister {id} is on subnet {subnet}, expected subnet {expected}"
+                                ));
+                            }
+                            IDLValue::Principal(subnet)
+                        }
+                        _ => return Err(anyhow!("assert_subnet expects (canister_id, subnet_id)")),
+                    },
+                    "logs" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id)] => {
+                            let records = crate::utils::fetch_canister_logs(&helper.agent, *id)?;
+                            if records.is_empty() {
+                                println!("Canister {id} has no log entries");
+                            }
+                            let mut res = Vec::with_capacity(records.len());
+                            for r in records {
+                                println!(
+                                    "[{}. {}]: {}",
+                                    r.idx,
+                                    crate::utils::format_timestamp(r.timestamp_nanos),
+                                    String::from_utf8_lossy(&r.content)
+                                );
+                                let mut fields = vec![
+                                    IDLField {
+                                        id: Label::Named("idx".to_string()),
+                                        val: IDLValue::Nat64(r.idx),
+                                    },
+                                    IDLField {
+                                        id: Label::Named("timestamp_nanos".to_string()),
+                                        val: IDLValue::Nat64(r.timestamp_nanos),
+                                    },
+                                    IDLField {
+                                        id: Label::Named("content".to_string()),
+                                        val: IDLValue::Blob(r.content),
+                                    },
+                                ];
+                                fields.sort_by_key(|f| f.id.get_id());
+                                res.push(IDLValue::Record(fields));
+                            }
+                            IDLValue::Vec(res)
+                        }
+                        _ => return Err(anyhow!("logs expects a canister id")),
+                    },
+                    "take_snapshot" => match args.as_slice() {
+                        [IDLValue::Principal(id)] => call_ic(
+                            helper,
+                            "take_canister_snapshot",
+                            vec![
+                                ("canister_id", IDLValue::Principal(*id)),
+                                ("replace_snapshot", IDLValue::None),
+                            ],
+                        )?,
+                        [IDLValue::Principal(id), snapshot] => call_ic(
+                            helper,
+                            "take_canister_snapshot",
+                            vec![
+                                ("canister_id", IDLValue::Principal(*id)),
+                                (
+                                    "replace_snapshot",
+                                    IDLValue::Opt(Box::new(IDLValue::Blob(snapshot_id(snapshot)?))),
+                                ),
+                            ],
+                        )?,
+                        _ => {
+                            return Err(anyhow!(
+                                "take_snapshot expects (canister_id) or (canister_id, snapshot_id)"
+                            ))
+                        }
+                    },
+                    "list_snapshots" => match args.as_slice() {
+                        [IDLValue::Principal(id)] => call_ic(
+                            helper,
+                            "list_canister_snapshots",
+                            vec![("canister_id", IDLValue::Principal(*id))],
+                        )?,
+                        _ => return Err(anyhow!("list_snapshots expects a canister id")),
+                    },
+                    "restore_snapshot" => match args.as_slice() {
+                        [IDLValue::Principal(id), snapshot] => call_ic(
+                            helper,
+                            "load_canister_snapshot",
+                            vec![
+                                ("canister_id", IDLValue::Principal(*id)),
+                                ("snapshot_id", IDLValue::Blob(snapshot_id(snapshot)?)),
+                                ("sender_canister_version", IDLValue::None),
+                            ],
+                        )?,
+                        _ => {
+                            return Err(anyhow!(
+                                "restore_snapshot expects (canister_id, snapshot_id)"
+                            ))
+                        }
+                    },
+                    // A user-defined deploy function, e.g., in examples/install.sh, takes precedence
+                    "deploy"
+                        if helper.offline.is_none()
+                            && !helper.func_env.0.contains_key("deploy") =>
+                    {
+                        let (wasm, arg, settings) = match args.as_slice() {
+                            [IDLValue::Blob(wasm), arg] => (wasm, arg, &IDLValue::None),
+                            [IDLValue::Blob(wasm), arg, settings] => (wasm, arg, settings),
+                            _ => {
+                                return Err(anyhow!(
+                                    "deploy expects (wasm, init_args, opt settings)"
+                                ))
+                            }
+                        };
+                        let settings = match settings {
+                            IDLValue::Null | IDLValue::None | IDLValue::Opt(_) => settings.clone(),
+                            v => IDLValue::Opt(Box::new(v.clone())),
+                        };
+                        let arg = init_arg_bytes(arg)?;
+                        let res = if helper.wallet.is_some() {
+                            call_ic(
+                                helper,
+                                "create_canister",
+                                vec![
+                                    ("settings", settings),
+                                    ("sender_canister_version", IDLValue::None),
+                                ],
+                            )?
+                        } else {
+                            call_ic(
+                                helper,
+                                "provisional_create_canister_with_cycles",
+                                vec![("settings", settings), ("amount", IDLValue::None)],
+                            )?
+                        };
+                        let id = match &res {
+                            IDLValue::Record(fs) => match get_field(fs, "canister_id") {
+                                Some(IDLValue::Principal(id)) => *id,
+                                _ => {
+                                    return Err(anyhow!(
+                                        "unexpected reply from create_canister: {res}"
+                                    ))
+                                }
+                            },
+                            _ => {
+                                return Err(anyhow!("unexpected reply from create_canister: {res}"))
+                            }
+                        };
+                        if helper.verbose {
+                            println!("Created canister {id}");
+                        }
+                        install_code(helper, id, wasm.clone(), arg, install_mode("install", None))?;
+                        if helper.verbose {
+                            println!("Installed {} bytes of Wasm module to {id}", wasm.len());
+                        }
+                        IDLValue::Principal(id)
+                    }
+                    "install" | "reinstall" | "upgrade" => {
+                        let (id, wasm, arg, opts) = match args.as_slice() {
+                            [IDLValue::Principal(id), IDLValue::Blob(wasm), arg] => {
+                                (id, wasm, arg, None)
+                            }
+                            [IDLValue::Principal(id), IDLValue::Blob(wasm), arg, opts]
+                                if func == "upgrade" =>
+                            {
+                                (id, wasm, arg, Some(opts))
+                            }
+                            _ if func == "upgrade" => {
+                                return Err(anyhow!(
+                                    "upgrade expects (canister_id, wasm, args, opt upgrade_options)"
+                                ))
+                            }
+                            _ => {
+                                return Err(anyhow!(
+                                    "{func} expects (canister_id, wasm, init_args)"
+                                ))
+                            }
+                        };
+                        if func != "install" && helper.offline.is_none() {
+                            let status = call_ic(
+                                helper,
+                                "canister_status",
+                                vec![("canister_id", IDLValue::Principal(*id))],
+                            )
+                            .with_context(|| format!("Cannot {func} canister {id}"))?;
+                            let has_module = matches!(&status, IDLValue::Record(fs) if matches!(get_field(fs, "module_hash"), Some(IDLValue::Opt(_))));
+                            if func == "upgrade" && !has_module {
+                                return Err(anyhow!(
+                                    "canister {id} has no Wasm module to upgrade, use install instead"
+                                ));
+                            }
+                        }
+                        let arg = init_arg_bytes(arg)?;
+                        install_code(helper, *id, wasm.clone(), arg, install_mode(&func, opts))?
+                    }
+                    "cleanup_canister" => {
+                        let [IDLValue::Principal(id)] = args.as_slice() else {
+                            return Err(anyhow!("cleanup_canister expects a canister id"));
+                        };
+                        let canister_id = vec![("canister_id", IDLValue::Principal(*id))];
+                        let status = call_ic(helper, "canister_status", canister_id.clone())
+                            .with_context(|| format!("Cannot stop canister {id}"))?;
+                        let stopped = matches!(&status, IDLValue::Record(fs) if matches!(get_field(fs, "status"), Some(IDLValue::Variant(VariantValue(f, _))) if f.id == Label::Named("stopped".to_string())));
+                        if stopped {
+                            if helper.verbose {
+                                println!("Canister {id} is already stopped");
+                            }
+                        } else {
+                            call_ic(helper, "stop_canister", canister_id.clone())
+                                .with_context(|| format!("Cannot stop canister {id}"))?;
+                            if helper.verbose {
+                                println!("Stopped canister {id}");
+                            }
+                        }
+                        let res = call_ic(helper, "delete_canister", canister_id)
+                            .with_context(|| format!("Cannot delete canister {id}"))?;
+                        if helper.verbose {
+                            println!("Deleted canister {id}");
+                        }
+                        res
+                    }
+                    "watch_balance" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(ledger) | IDLValue::Service(ledger), account] => {
+                            watch_balance(helper, *ledger, account, 5)?
+                        }
+                        [IDLValue::Principal(ledger) | IDLValue::Service(ledger), account, interval] => {
+                            watch_balance(helper, *ledger, account, as_u32(interval)?)?
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "watch_balance expects (ledger, account, opt interval_in_seconds)"
+                            ))
+                        }
+                    },
+                    "streaming_body" if helper.offline.is_none() => match args.as_slice() {
+                        [response] => IDLValue::Blob(streaming_body(helper, response, 100 << 20)?),
+                        [response, max_bytes] => IDLValue::Blob(streaming_body(
+                            helper,
+                            response,
+                            as_u32(max_bytes)? as usize,
+                        )?),
+                        _ => {
+                            return Err(anyhow!("streaming_body expects (response, opt max_bytes)"))
+                        }
+                    },
+                    "try_parse" => match args.as_slice() {
+                        [IDLValue::Text(code)] => {
+                            let (label, val) = match code.parse::<crate::command::Commands>() {
+                                Ok(_) => ("ok", IDLValue::Null),
+                                Err(e) => {
+                                    let (span, message) = crate::error::error_message(&e);
+                                    let start = span.start.min(code.len());
+                                    let before = &code[..start];
+                                    let line = before.matches('\n').count() + 1;
+                                    let column =
+                                        before.rsplit('\n').next().unwrap().chars().count() + 1;
+                                    let mut fs = vec![
+                                        IDLField {
+                                            id: Label::Named("message".to_string()),
+                                            val: IDLValue::Text(message),
+                                        },
+                                        IDLField {
+                                            id: Label::Named("line".to_string()),
+                                            val: IDLValue::Nat(line.into()),
+                                        },
+                                        IDLField {
+                                            id: Label::Named("column".to_string()),
+                                            val: IDLValue::Nat(column.into()),
+                                        },
+                                    ];
+                                    fs.sort_by_key(|f| f.id.get_id());
+                                    ("err", IDLValue::Record(fs))
+                                }
+                            };
+                            IDLValue::Variant(VariantValue(
+                                Box::new(IDLField {
+                                    id: Label::Named(label.to_string()),
+                                    val,
+                                }),
+                                0,
+                            ))
+                        }
+                        _ => return Err(anyhow!("try_parse expects a text")),
+                    },
                     "file" => match args.as_slice() {
                         [IDLValue::Text(file)] => {
                             let path = resolve_path(&helper.base_path, file);
@@ -217,6 +726,214 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("file expects file path")),
                     },
+                    "read_csv" => match args.as_slice() {
+                        [IDLValue::Text(file)] => {
+                            let path = resolve_path(&helper.base_path, file);
+                            let csv = std::fs::read_to_string(&path)
+                                .with_context(|| format!("Cannot read {path:?}"))?;
+                            crate::utils::csv_to_value(&csv)
+                                .with_context(|| format!("Cannot parse {path:?}"))?
+                        }
+                        _ => return Err(anyhow!("read_csv expects file path")),
+                    },
+                    "read_json" => match args.as_slice() {
+                        [IDLValue::Text(file)] => {
+                            let path = resolve_path(&helper.base_path, file);
+                            let json = std::fs::read_to_string(&path)
+                                .with_context(|| format!("Cannot read {path:?}"))?;
+                            let json = serde_json::from_str(&json)
+                                .with_context(|| format!("Cannot parse {path:?}"))?;
+                            crate::utils::json_to_value(json)
+                        }
+                        _ => return Err(anyhow!("read_json expects file path")),
+                    },
+                    "type_of" => match args.as_slice() {
+                        [IDLValue::Number(n)] if helper.nat_default && !n.starts_with('-') => {
+                            IDLValue::Text("nat".to_string())
+                        }
+                        [v] if as_func(helper, v).is_some() => {
+                            IDLValue::Text("function".to_string())
+                        }
+                        [v] => IDLValue::Text(v.value_ty().to_string()),
+                        _ => return Err(anyhow!("type_of expects one argument")),
+                    },
+                    "as_hex" => match args.as_slice() {
+                        [IDLValue::Blob(blob)] => IDLValue::Text(hex::encode(blob)),
+                        _ => return Err(anyhow!("as_hex expects a blob")),
+                    },
+                    "as_text" => match args.as_slice() {
+                        [IDLValue::Blob(blob)] => IDLValue::Text(
+                            String::from_utf8(blob.clone()).context("blob is not valid utf8")?,
+                        ),
+                        _ => return Err(anyhow!("as_text expects a blob")),
+                    },
+                    "tokens" => match args.as_slice() {
+                        [amount, decimals] => {
+                            let amount = match amount {
+                                IDLValue::Number(n) => n.clone(),
+                                IDLValue::Float64(f) => f.to_string(),
+                                IDLValue::Text(t) => t.clone(),
+                                IDLValue::Nat(n) => n.0.to_string(),
+                                _ => return Err(anyhow!("{amount} is not a token amount")),
+                            };
+                            IDLValue::Nat(crate::utils::scale_tokens(&amount, as_u32(decimals)?)?)
+                        }
+                        _ => return Err(anyhow!("tokens expects (amount, decimals)")),
+                    },
+                    "display_tokens" => match args.as_slice() {
+                        [n, decimals] => {
+                            let n = match n {
+                                IDLValue::Nat(n) => n.clone(),
+                                IDLValue::Number(n) => n.parse::<candid::Nat>()?,
+                                IDLValue::Nat64(n) => candid::Nat::from(*n),
+                                _ => return Err(anyhow!("{n} is not a nat")),
+                            };
+                            IDLValue::Text(crate::utils::display_tokens(&n, as_u32(decimals)?))
+                        }
+                        _ => return Err(anyhow!("display_tokens expects (nat, decimals)")),
+                    },
+                    "principal_from_bytes" => match args.as_slice() {
+                        [IDLValue::Blob(bytes)] => IDLValue::Principal(
+                            Principal::try_from_slice(bytes)
+                                .map_err(|e| anyhow!("invalid principal bytes: {e}"))?,
+                        ),
+                        _ => return Err(anyhow!("principal_from_bytes expects a blob")),
+                    },
+                    "same_principal" => match args.as_slice() {
+                        [v1, v2] => {
+                            let to_principal = |v: &IDLValue| match v {
+                                IDLValue::Principal(id) | IDLValue::Service(id) => Ok(*id),
+                                IDLValue::Text(text) => Principal::from_text(text.trim())
+                                    .map_err(|e| anyhow!("{text:?} is not a principal: {e}")),
+                                _ => Err(anyhow!(
+                                    "same_principal expects principal or text, found {v}"
+                                )),
+                            };
+                            IDLValue::Bool(
+                                to_principal(v1)?.as_slice() == to_principal(v2)?.as_slice(),
+                            )
+                        }
+                        _ => return Err(anyhow!("same_principal expects two principals")),
+                    },
+                    "random_self_auth_principal" => match args.as_slice() {
+                        [] => {
+                            use ic_agent::identity::{BasicIdentity, Identity};
+                            let identity = BasicIdentity::from_signing_key(
+                                ed25519_consensus::SigningKey::new(rand::thread_rng()),
+                            );
+                            IDLValue::Principal(identity.sender().map_err(|e| anyhow!("{e}"))?)
+                        }
+                        _ => {
+                            return Err(anyhow!("random_self_auth_principal expects no arguments"))
+                        }
+                    },
+                    "principal_from_seed" => match args.as_slice() {
+                        [IDLValue::Text(seed)] => {
+                            use ic_agent::identity::{BasicIdentity, Identity};
+                            use sha2::{Digest, Sha256};
+                            let bytes: [u8; 32] = Sha256::digest(seed.as_bytes()).into();
+                            let identity = BasicIdentity::from_signing_key(
+                                ed25519_consensus::SigningKey::from(bytes),
+                            );
+                            IDLValue::Principal(identity.sender().map_err(|e| anyhow!("{e}"))?)
+                        }
+                        _ => return Err(anyhow!("principal_from_seed expects a text seed")),
+                    },
+                    "ii_principal" => match args.as_slice() {
+                        [IDLValue::Principal(ii), IDLValue::Blob(salt), anchor, IDLValue::Text(origin)] =>
+                        {
+                            let IDLValue::Nat64(anchor) =
+                                cast_type(anchor.clone(), &TypeInner::Nat64.into())?
+                            else {
+                                unreachable!()
+                            };
+                            IDLValue::Principal(crate::utils::ii_principal(
+                                ii, salt, anchor, origin,
+                            )?)
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                            "ii_principal expects (ii_canister_id, salt, anchor, frontend_origin)"
+                        ))
+                        }
+                    },
+                    "next_canister_id" => match args.as_slice() {
+                        [IDLValue::Principal(id)] => {
+                            IDLValue::Principal(crate::utils::offset_canister_id(id, 1)?)
+                        }
+                        _ => return Err(anyhow!("next_canister_id expects a canister id")),
+                    },
+                    "canister_id_range" => match args.as_slice() {
+                        [IDLValue::Principal(id), count] => {
+                            let count = as_u32(count)?;
+                            let mut res = Vec::with_capacity(count as usize);
+                            for i in 0..count {
+                                res.push(IDLValue::Principal(crate::utils::offset_canister_id(
+                                    id, i as u64,
+                                )?));
+                            }
+                            IDLValue::Vec(res)
+                        }
+                        _ => return Err(anyhow!("canister_id_range expects (canister_id, count)")),
+                    },
+                    "memo" => match args.as_slice() {
+                        [IDLValue::Number(n)] => IDLValue::Blob(
+                            n.replace('_', "")
+                                .parse::<u64>()
+                                .with_context(|| format!("{n} is not a nat64"))?
+                                .to_be_bytes()
+                                .to_vec(),
+                        ),
+                        [IDLValue::Nat64(n)] => IDLValue::Blob(n.to_be_bytes().to_vec()),
+                        _ => return Err(anyhow!("memo expects a nat64")),
+                    },
+                    "memo_text" => match args.as_slice() {
+                        [IDLValue::Text(text)] => {
+                            // Default max memo size of ICRC-1 ledgers
+                            const MAX_MEMO_BYTES: usize = 32;
+                            if text.len() > MAX_MEMO_BYTES {
+                                return Err(anyhow!(
+                                    "memo is {} bytes, exceeding the ledger limit of {MAX_MEMO_BYTES} bytes",
+                                    text.len()
+                                ));
+                            }
+                            IDLValue::Blob(text.as_bytes().to_vec())
+                        }
+                        _ => return Err(anyhow!("memo_text expects a text")),
+                    },
+                    "now" => match args.as_slice() {
+                        [] => IDLValue::Nat64(crate::utils::now_nanos()?),
+                        _ => return Err(anyhow!("now expects no arguments")),
+                    },
+                    "sleep_until" => match args.as_slice() {
+                        [t] => {
+                            let target = crate::utils::as_int(t)
+                                .ok_or_else(|| anyhow!("{t} is not a timestamp"))?
+                                .0
+                                .to_string()
+                                .parse::<i128>()?;
+                            let delay = target - crate::utils::now_nanos()? as i128;
+                            if delay > 0 {
+                                std::thread::sleep(std::time::Duration::from_nanos(delay as u64));
+                            }
+                            IDLValue::Null
+                        }
+                        _ => return Err(anyhow!("sleep_until expects a nanosecond timestamp")),
+                    },
+                    "format_number" => match args.as_slice() {
+                        [v] => IDLValue::Text(
+                            crate::utils::format_integer(v)
+                                .ok_or_else(|| anyhow!("{v} is not an integer"))?,
+                        ),
+                        _ => return Err(anyhow!("format_number expects an integer")),
+                    },
+                    "blob_from_hex" => match args.as_slice() {
+                        [IDLValue::Text(str)] => IDLValue::Blob(
+                            hex::decode(str)
+                                .with_context(|| format!("{str} is not a hex string"))?,
+                        ),
+                        _ => return Err(anyhow!("blob_from_hex expects a hex string")),
+                    },
                     "gzip" => match args.as_slice() {
                         [IDLValue::Blob(blob)] => {
                             use libflate::gzip::Encoder;
@@ -444,6 +1161,12 @@ impl Exp {
                         }
                         IDLValue::Text(res)
                     }
+                    "format" => match args.split_first() {
+                        Some((IDLValue::Text(template), args)) => {
+                            IDLValue::Text(crate::utils::format_template(template, args)?)
+                        }
+                        _ => return Err(anyhow!("format expects a text template")),
+                    },
                     "concat" => match args.as_slice() {
                         [IDLValue::Vec(s1), IDLValue::Vec(s2)] => {
                             let mut res = Vec::from(s1.as_slice());
@@ -467,6 +1190,210 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("concat expects two vec, record or text")),
                     },
+                    "merge" => match args.as_slice() {
+                        [base @ IDLValue::Record(_), overrides @ IDLValue::Record(_)] => {
+                            crate::utils::merge_records(base.clone(), overrides.clone(), "")?
+                        }
+                        _ => return Err(anyhow!("merge expects two records")),
+                    },
+                    "contains" => match args.as_slice() {
+                        [IDLValue::Vec(vs), v] => {
+                            IDLValue::Bool(vs.iter().any(|e| crate::utils::typed_eq(e, v)))
+                        }
+                        [IDLValue::Text(s1), IDLValue::Text(s2)] => IDLValue::Bool(s1.contains(s2)),
+                        _ => return Err(anyhow!("contains expects (vec, value) or (text, text)")),
+                    },
+                    "slice" | "take" | "drop" => {
+                        let (v, start, end) = match (func.as_str(), args.as_slice()) {
+                            ("slice", [v, start, end]) => {
+                                (v, as_u32(start)? as usize, Some(as_u32(end)? as usize))
+                            }
+                            ("take", [v, n]) => (v, 0, Some(as_u32(n)? as usize)),
+                            ("drop", [v, n]) => (v, as_u32(n)? as usize, None),
+                            ("slice", _) => return Err(anyhow!("slice expects (vec, start, end)")),
+                            _ => return Err(anyhow!("{func} expects (vec, n)")),
+                        };
+                        let strict = matches!(
+                            helper.env.0.get("__strict_bounds"),
+                            Some(IDLValue::Bool(true))
+                        );
+                        crate::utils::slice_value(v, start, end, strict)?
+                    }
+                    "unique" => match args.as_slice() {
+                        [IDLValue::Vec(vs)] => {
+                            let mut res: Vec<IDLValue> = Vec::new();
+                            for v in vs {
+                                if !res.contains(v) {
+                                    res.push(v.clone());
+                                }
+                            }
+                            IDLValue::Vec(res)
+                        }
+                        _ => return Err(anyhow!("unique expects a vec")),
+                    },
+                    "zip" => match args.as_slice() {
+                        [IDLValue::Vec(v1), IDLValue::Vec(v2)] => {
+                            if v1.len() != v2.len() {
+                                return Err(anyhow!(
+                                    "zip expects two vectors of the same length, found {} and {}",
+                                    v1.len(),
+                                    v2.len()
+                                ));
+                            }
+                            IDLValue::Vec(
+                                v1.iter()
+                                    .zip(v2.iter())
+                                    .map(|(a, b)| {
+                                        IDLValue::Record(vec![
+                                            IDLField {
+                                                id: Label::Id(0),
+                                                val: a.clone(),
+                                            },
+                                            IDLField {
+                                                id: Label::Id(1),
+                                                val: b.clone(),
+                                            },
+                                        ])
+                                    })
+                                    .collect(),
+                            )
+                        }
+                        _ => return Err(anyhow!("zip expects two vectors")),
+                    },
+                    "enumerate" => match args.as_slice() {
+                        [IDLValue::Vec(vs)] => IDLValue::Vec(
+                            vs.iter()
+                                .enumerate()
+                                .map(|(i, v)| {
+                                    let mut fs = vec![
+                                        IDLField {
+                                            id: Label::Named("index".to_string()),
+                                            val: IDLValue::Nat(i.into()),
+                                        },
+                                        IDLField {
+                                            id: Label::Named("value".to_string()),
+                                            val: v.clone(),
+                                        },
+                                    ];
+                                    fs.sort_by_key(|f| f.id.get_id());
+                                    IDLValue::Record(fs)
+                                })
+                                .collect(),
+                        ),
+                        _ => return Err(anyhow!("enumerate expects a vec")),
+                    },
+                    "reverse" => match args.as_slice() {
+                        [IDLValue::Vec(vs)] => IDLValue::Vec(vs.iter().rev().cloned().collect()),
+                        [IDLValue::Blob(b)] => IDLValue::Blob(b.iter().rev().cloned().collect()),
+                        [IDLValue::Text(s)] => IDLValue::Text(s.chars().rev().collect()),
+                        _ => return Err(anyhow!("reverse expects a vec or text")),
+                    },
+                    "starts_with" | "ends_with" => match args.as_slice() {
+                        [IDLValue::Text(s), IDLValue::Text(pat)] => {
+                            IDLValue::Bool(if func == "starts_with" {
+                                s.starts_with(pat.as_str())
+                            } else {
+                                s.ends_with(pat.as_str())
+                            })
+                        }
+                        _ => return Err(anyhow!("{func} expects (text, text)")),
+                    },
+                    "replace" => match args.as_slice() {
+                        [IDLValue::Text(s), IDLValue::Text(from), IDLValue::Text(to)] => {
+                            if from.is_empty() {
+                                return Err(anyhow!("replace expects a non-empty pattern"));
+                            }
+                            IDLValue::Text(s.replace(from.as_str(), to))
+                        }
+                        _ => return Err(anyhow!("replace expects (text, from, to)")),
+                    },
+                    "to_upper" | "to_lower" | "trim" => match args.as_slice() {
+                        [IDLValue::Text(s)] => IDLValue::Text(match func.as_str() {
+                            "to_upper" => s.to_uppercase(),
+                            "to_lower" => s.to_lowercase(),
+                            "trim" => s.trim().to_string(),
+                            _ => unreachable!(),
+                        }),
+                        _ => return Err(anyhow!("{func} expects a text")),
+                    },
+                    "split" => match args.as_slice() {
+                        [IDLValue::Text(s), IDLValue::Text(sep)] => {
+                            if sep.is_empty() {
+                                return Err(anyhow!("split expects a non-empty separator"));
+                            }
+                            IDLValue::Vec(
+                                s.split(sep.as_str())
+                                    .map(|s| IDLValue::Text(s.to_string()))
+                                    .collect(),
+                            )
+                        }
+                        _ => return Err(anyhow!("split expects (text, separator)")),
+                    },
+                    "field_hash" => match args.as_slice() {
+                        [IDLValue::Text(name)] => IDLValue::Nat32(candid::idl_hash(name)),
+                        _ => return Err(anyhow!("field_hash expects a text")),
+                    },
+                    "resolve_fields" => match args.as_slice() {
+                        [v, IDLValue::Vec(names)] => {
+                            let mut labels = BTreeMap::new();
+                            for name in names {
+                                let IDLValue::Text(name) = name else {
+                                    return Err(anyhow!(
+                                        "resolve_fields expects a vector of text, found {name}"
+                                    ));
+                                };
+                                labels.insert(candid::idl_hash(name), name.clone());
+                            }
+                            crate::utils::resolve_labels(v.clone(), &labels)
+                        }
+                        _ => return Err(anyhow!("resolve_fields expects (value, vec text)")),
+                    },
+                    "select" => match args.as_slice() {
+                        [v, IDLValue::Text(path)] => {
+                            crate::selector::select(helper, v.clone(), path)?
+                        }
+                        _ => return Err(anyhow!("select expects (value, path)")),
+                    },
+                    "is_anonymous" => match args.as_slice() {
+                        [IDLValue::Principal(id)] => IDLValue::Bool(*id == Principal::anonymous()),
+                        _ => return Err(anyhow!("is_anonymous expects a principal")),
+                    },
+                    "sort" => match args.as_slice() {
+                        [IDLValue::Vec(vs)] => {
+                            IDLValue::Vec(sort_by_key(vs.to_vec(), |v| Ok(v.clone()))?)
+                        }
+                        _ => return Err(anyhow!("sort expects a vec")),
+                    },
+                    "sort_by" => match args.as_slice() {
+                        [IDLValue::Vec(vs), IDLValue::Text(field)] => {
+                            IDLValue::Vec(sort_by_key(vs.to_vec(), |v| match v {
+                                IDLValue::Record(fs) => get_field(fs, field)
+                                    .cloned()
+                                    .ok_or_else(|| anyhow!("record field {field} not found")),
+                                _ => Err(anyhow!("sort_by expects a vec of records")),
+                            })?)
+                        }
+                        _ => return Err(anyhow!("sort_by expects (vec, field name)")),
+                    },
+                    "assert_sorted" => match args.as_slice() {
+                        [v @ IDLValue::Vec(vs)] => {
+                            crate::utils::check_sorted(vs, |v| Ok(v.clone()))?;
+                            v.clone()
+                        }
+                        _ => return Err(anyhow!("assert_sorted expects a vec")),
+                    },
+                    "assert_sorted_by" => match args.as_slice() {
+                        [v @ IDLValue::Vec(vs), IDLValue::Text(field)] => {
+                            crate::utils::check_sorted(vs, |v| match v {
+                                IDLValue::Record(fs) => get_field(fs, field)
+                                    .cloned()
+                                    .ok_or_else(|| anyhow!("record field {field} not found")),
+                                _ => Err(anyhow!("assert_sorted_by expects a vec of records")),
+                            })?;
+                            v.clone()
+                        }
+                        _ => return Err(anyhow!("assert_sorted_by expects (vec, field name)")),
+                    },
                     "eq" | "neq" => match args.as_slice() {
                         [v1, v2] => {
                             if v1.value_ty() != v2.value_ty() {
@@ -497,56 +1424,24 @@ impl Exp {
                         [IDLValue::Bool(v)] => IDLValue::Bool(!v),
                         _ => return Err(anyhow!("not expects a bool value")),
                     },
-                    "lt" | "lte" | "gt" | "gte" | "add" | "sub" | "mul" | "div" => match args
-                        .as_slice()
-                    {
-                        [IDLValue::Float32(_) | IDLValue::Float64(_), _]
-                        | [_, IDLValue::Float32(_) | IDLValue::Float64(_)] => {
-                            let IDLValue::Float64(v1) =
-                                cast_type(args[0].clone(), &TypeInner::Float64.into())?
-                            else {
-                                panic!()
-                            };
-                            let IDLValue::Float64(v2) =
-                                cast_type(args[1].clone(), &TypeInner::Float64.into())?
-                            else {
-                                panic!()
-                            };
-                            match func.as_str() {
-                                "add" => IDLValue::Float64(v1 + v2),
-                                "sub" => IDLValue::Float64(v1 - v2),
-                                "mul" => IDLValue::Float64(v1 * v2),
-                                "div" => IDLValue::Float64(v1 / v2),
-                                "lt" => IDLValue::Bool(v1 < v2),
-                                "lte" => IDLValue::Bool(v1 <= v2),
-                                "gt" => IDLValue::Bool(v1 > v2),
-                                "gte" => IDLValue::Bool(v1 >= v2),
-                                _ => unreachable!(),
-                            }
+                    "lt" | "lte" | "gt" | "gte" | "add" | "sub" | "mul" | "div" => {
+                        match args.as_slice() {
+                            [v1, v2] => arith(&func, v1, v2)?,
+                            _ => return Err(anyhow!("{func} expects two numbers")),
                         }
-                        [v1, v2] => {
-                            let IDLValue::Int(v1) = cast_type(v1.clone(), &TypeInner::Int.into())?
-                            else {
-                                panic!()
-                            };
-                            let IDLValue::Int(v2) = cast_type(v2.clone(), &TypeInner::Int.into())?
-                            else {
-                                panic!()
-                            };
-                            match func.as_str() {
-                                "add" => IDLValue::Number((v1 + v2).to_string()),
-                                "sub" => IDLValue::Number((v1 - v2).to_string()),
-                                "mul" => IDLValue::Number((v1 * v2).to_string()),
-                                "div" => IDLValue::Number((v1 / v2).to_string()),
-                                "lt" => IDLValue::Bool(v1 < v2),
-                                "lte" => IDLValue::Bool(v1 <= v2),
-                                "gt" => IDLValue::Bool(v1 > v2),
-                                "gte" => IDLValue::Bool(v1 >= v2),
-                                _ => unreachable!(),
-                            }
+                    }
+                    "sum" => {
+                        let vs = match args.as_slice() {
+                            [IDLValue::Vec(vs)] => vs.clone(),
+                            [IDLValue::Blob(b)] => b.iter().map(|n| IDLValue::Nat8(*n)).collect(),
+                            _ => return Err(anyhow!("sum expects a vec of numbers")),
+                        };
+                        let mut acc = IDLValue::Number("0".to_string());
+                        for v in vs.iter() {
+                            acc = arith("add", &acc, v)?;
                         }
-                        _ => return Err(anyhow!("{func} expects two numbers")),
-                    },
+                        acc
+                    }
                     func => apply_func(helper, func, args)?,
                 }
             }
@@ -586,12 +1481,13 @@ impl Exp {
                     for arg in call.args.into_iter() {
                         args.push(arg.eval(helper)?);
                     }
-                    let args = IDLArgs { args };
+                    let mut args = IDLArgs { args };
                     let info = call.method.get_info(helper, false)?;
                     let bytes = if let Some((env, func)) = &info.signature {
+                        check_arity(&call.method.method, env, func, &mut args)?;
                         args.to_bytes_with_types(env, &func.args)?
                     } else {
-                        args.to_bytes()?
+                        crate::utils::untyped_args(args, helper).to_bytes()?
                     };
                     let method = &call.method.method;
                     let effective_id = get_effective_canister_id(info.canister_id, method, &bytes)?;
@@ -599,8 +1495,10 @@ impl Exp {
                     builder = builder
                         .with_arg(bytes)
                         .with_effective_canister_id(effective_id);
+                    let max_bytes = helper.max_response_bytes;
                     let call_future = async move {
                         let res = builder.call_and_wait().await?;
+                        check_response_size(&res, max_bytes)?;
                         if let Some((env, func)) = &info.signature {
                             Ok(IDLArgs::from_bytes_with_types(&res, env, &func.rets)?)
                         } else {
@@ -615,6 +1513,22 @@ impl Exp {
                 };
                 args_to_value(res)
             }
+            Exp::Call {
+                method: Some(mut method),
+                args,
+                mode: CallMode::Dynamic(name),
+            } => {
+                let IDLValue::Text(name) = name.eval(helper)? else {
+                    return Err(anyhow!("method name needs to be a text value"));
+                };
+                method.method = name;
+                Exp::Call {
+                    method: Some(method),
+                    args,
+                    mode: CallMode::Call,
+                }
+                .eval(helper)?
+            }
             Exp::Call { method, args, mode } => {
                 let args = if let Some(args) = args {
                     let mut res = Vec::with_capacity(args.len());
@@ -636,7 +1550,7 @@ impl Exp {
                     ..
                 }) = &opt_info
                 {
-                    let args = if let Some(args) = args {
+                    let mut args = if let Some(args) = args {
                         args
                     } else {
                         use candid_parser::assist::{input_args, Context};
@@ -657,25 +1571,47 @@ impl Exp {
                         }
                         args
                     };
+                    let name = method.as_ref().map(|m| m.method.as_str()).unwrap_or("init");
+                    check_arity(name, env, func, &mut args)?;
                     args.to_bytes_with_types(env, &func.args)?
                 } else {
                     if args.is_none() {
                         return Err(anyhow!("cannot get method type, please provide arguments"));
                     }
-                    args.unwrap().to_bytes()?
+                    crate::utils::untyped_args(args.unwrap(), helper).to_bytes()?
                 };
                 match mode {
                     CallMode::Encode => IDLValue::Blob(bytes),
-                    CallMode::Call => {
+                    CallMode::Call | CallMode::CallAs(_) => {
                         use crate::profiling::{get_cycles, ok_to_profile};
                         let method = method.unwrap(); // okay to unwrap from parser
-                        let info = opt_info.unwrap();
+                        let mut info = opt_info.unwrap();
+                        if let CallMode::CallAs(rets) = &mode {
+                            // Keep the declared argument types, and only override the reply types
+                            let (env, mut func) = info.signature.take().unwrap_or_else(|| {
+                                (
+                                    TypeEnv::new(),
+                                    Function {
+                                        args: Vec::new(),
+                                        rets: Vec::new(),
+                                        modes: Vec::new(),
+                                    },
+                                )
+                            });
+                            let mut types = Vec::with_capacity(rets.len());
+                            for ty in rets.iter() {
+                                types.push(candid_parser::typing::ast_to_type(&env, ty)?);
+                            }
+                            func.rets = types;
+                            info.signature = Some((env, func));
+                        }
                         let ok_to_profile = ok_to_profile(helper, &info);
                         let before_cost = if ok_to_profile {
                             get_cycles(&helper.agent, &info.canister_id)?
                         } else {
                             0
                         };
+                        let time = std::time::Instant::now();
                         let res = call(
                             helper,
                             &info.canister_id,
@@ -684,6 +1620,7 @@ impl Exp {
                             &info.signature,
                             &helper.offline,
                         )?;
+                        helper.last_call_duration.set(Some(time.elapsed()));
                         if ok_to_profile {
                             let cost = get_cycles(&helper.agent, &info.canister_id)? - before_cost;
                             println!("Cost: {cost} Wasm instructions");
@@ -697,6 +1634,22 @@ impl Exp {
                             args_to_value(res)
                         }
                     }
+                    CallMode::RawReply => {
+                        let method = method.unwrap();
+                        let info = opt_info.unwrap();
+                        let time = std::time::Instant::now();
+                        let res = call_raw_reply(
+                            helper,
+                            &info.canister_id,
+                            &method.method,
+                            &bytes,
+                            &info.signature,
+                            &helper.offline,
+                        )?;
+                        helper.last_call_duration.set(Some(time.elapsed()));
+                        IDLValue::Blob(res)
+                    }
+                    CallMode::Dynamic(_) => unreachable!(),
                     CallMode::Proxy(id) => {
                         let method = method.unwrap();
                         let canister_id = str_to_principal(&method.canister, helper)?;
@@ -751,7 +1704,12 @@ let _ = decode as "{canister}".{method} _.Ok.return;
             Exp::Float64(f) => IDLValue::Float64(f),
             Exp::Principal(id) => IDLValue::Principal(id),
             Exp::Service(id) => IDLValue::Service(id),
-            Exp::Func(id, meth) => IDLValue::Func(id, meth),
+            Exp::Func(id, meth) => {
+                if id == func_principal() {
+                    return Err(anyhow!("{id} is reserved for function values"));
+                }
+                IDLValue::Func(id, meth)
+            }
             Exp::Opt(v) => IDLValue::Opt(Box::new((*v).eval(helper)?)),
             Exp::Blob(b) => IDLValue::Blob(b),
             Exp::Vec(vs) => {
@@ -841,7 +1799,18 @@ impl Method {
                 }
             }
         }
-        let canister_id = str_to_principal(&self.canister, helper)?;
+        let canister = if self.canister.is_empty() {
+            helper.default_canister.as_deref().ok_or_else(|| {
+                anyhow!(
+                    "no default canister to call {}, use canister.{} or set a default canister with use <canister>",
+                    self.method,
+                    self.method
+                )
+            })?
+        } else {
+            &self.canister
+        };
+        let canister_id = str_to_principal(canister, helper)?;
         let agent = &helper.agent;
         let mut map = helper.canister_map.borrow_mut();
         Ok(match map.get(agent, &canister_id) {
@@ -872,7 +1841,7 @@ impl Method {
                             if !self.method.starts_with("__") {
                                 eprintln!(
                                     "Warning: cannot get type for {}.{}, use types infered from textual value",
-                                    self.canister, self.method
+                                    canister, self.method
                                 );
                             }
                             None
@@ -889,30 +1858,402 @@ impl Method {
     }
 }
 
+/// A function value is a func reference to a reserved principal, which no canister or user
+/// literal can have. The method name is the function name, followed by `#<n>` when `partial`
+/// bound arguments, which are kept at index `n` of `helper.func_args`.
+fn func_principal() -> Principal {
+    Principal::from_slice(b"ic-repl function\x7f")
+}
+fn func_value(helper: &MyHelper, func: String, args: Vec<IDLValue>) -> IDLValue {
+    let method = if args.is_empty() {
+        func
+    } else {
+        let mut func_args = helper.func_args.borrow_mut();
+        func_args.push(args);
+        format!("{func}#{}", func_args.len() - 1)
+    };
+    IDLValue::Func(func_principal(), method)
+}
+pub fn as_func(helper: &MyHelper, v: &IDLValue) -> Option<(String, Vec<IDLValue>)> {
+    match v {
+        IDLValue::Func(id, method) if *id == func_principal() => match method.split_once('#') {
+            None => Some((method.clone(), Vec::new())),
+            Some((func, n)) => {
+                let args = helper
+                    .func_args
+                    .borrow()
+                    .get(n.parse::<usize>().ok()?)?
+                    .clone();
+                Some((func.to_string(), args))
+            }
+        },
+        _ => None,
+    }
+}
 pub fn apply_func(helper: &MyHelper, func: &str, args: Vec<IDLValue>) -> Result<IDLValue> {
-    match helper.func_env.0.get(func) {
-        None => Err(anyhow!("Unknown function {}", func)),
+    // A variable holding a function value shadows the function of the same name
+    let (name, bound) = match helper.env.0.get(func) {
+        Some(v) => match as_func(helper, v) {
+            Some(f) => f,
+            None if helper.func_env.0.contains_key(func) => (func.to_string(), Vec::new()),
+            None => return Err(anyhow!("{} is not a function: {}", func, v)),
+        },
+        None => (func.to_string(), Vec::new()),
+    };
+    match helper.func_env.0.get(&name) {
+        None => Err(anyhow!("Unknown function {}", name)),
         Some((formal_args, body)) => {
-            if formal_args.len() != args.len() {
+            if formal_args.len() != bound.len() + args.len() {
                 return Err(anyhow!(
                     "{} expects {} arguments, but {} is provided",
                     func,
-                    formal_args.len(),
+                    formal_args.len().saturating_sub(bound.len()),
                     args.len()
                 ));
             }
             let mut helper = helper.spawn();
-            for (id, v) in formal_args.iter().zip(args.into_iter()) {
+            for (id, v) in formal_args.iter().zip(bound.into_iter().chain(args)) {
                 helper.env.0.insert(id.to_string(), v);
             }
-            for cmd in body.iter() {
-                cmd.clone().run(&mut helper)?;
+            crate::command::with_defer_scope(&mut helper, |helper| {
+                for cmd in body.iter() {
+                    cmd.clone().run(helper)?;
+                }
+                Ok(helper.env.0.get("_").unwrap_or(&IDLValue::Null).clone())
+            })
+        }
+    }
+}
+/// Make concurrent query calls. Update calls are rejected, as their order of execution is not defined.
+pub fn parallel_queries(helper: &MyHelper, calls: Vec<FuncCall>) -> Result<Vec<IDLValue>> {
+    let mut futures = Vec::with_capacity(calls.len());
+    for call in calls {
+        let mut args = Vec::with_capacity(call.args.len());
+        for arg in call.args.into_iter() {
+            args.push(arg.eval(helper)?);
+        }
+        let mut args = IDLArgs { args };
+        let info = call.method.get_info(helper, false)?;
+        let method = &call.method.method;
+        let Some((env, func)) = info.signature else {
+            return Err(anyhow!(
+                "cannot get the type of {}.{method}, only query methods are allowed in parallel",
+                call.method.canister
+            ));
+        };
+        if !func.is_query() {
+            return Err(anyhow!(
+                "{}.{method} is not a query method, only query methods are allowed in parallel",
+                call.method.canister
+            ));
+        }
+        check_arity(method, &env, &func, &mut args)?;
+        let bytes = args.to_bytes_with_types(&env, &func.args)?;
+        let effective_id = get_effective_canister_id(info.canister_id, method, &bytes)?;
+        let builder = helper
+            .agent
+            .query(&info.canister_id, method)
+            .with_arg(bytes)
+            .with_effective_canister_id(effective_id);
+        let max_bytes = helper.max_response_bytes;
+        futures.push(async move {
+            let res = builder.call().await?;
+            check_response_size(&res, max_bytes)?;
+            Ok(IDLArgs::from_bytes_with_types(&res, &env, &func.rets)?)
+        });
+    }
+    let res = parallel_calls(futures)?;
+    Ok(res.into_iter().map(args_to_value).collect())
+}
+/// Accept either a snapshot id, or a snapshot record returned by `take_snapshot` or `list_snapshots`.
+fn snapshot_id(v: &IDLValue) -> Result<Vec<u8>> {
+    match v {
+        IDLValue::Blob(id) => Ok(id.clone()),
+        IDLValue::Record(fs) => match crate::utils::get_field(fs, "id") {
+            Some(id) => snapshot_id(id),
+            None => Err(anyhow!("{v} is not a snapshot")),
+        },
+        _ => Err(anyhow!("{v} is not a snapshot id")),
+    }
+}
+/// Convert an optional 32-byte blob to a ledger subaccount.
+fn as_subaccount(v: Option<&IDLValue>) -> Result<Option<crate::account_identifier::Subaccount>> {
+    let v = match v {
+        Some(IDLValue::Opt(v)) => Some(v.as_ref()),
+        v => v,
+    };
+    Ok(match v {
+        None | Some(IDLValue::Null | IDLValue::None) => None,
+        Some(IDLValue::Blob(b)) => Some(crate::account_identifier::Subaccount(
+            b.as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("subaccount needs to be 32 bytes, found {}", b.len()))?,
+        )),
+        Some(v) => return Err(anyhow!("{v} is not a subaccount")),
+    })
+}
+/// Encode the init args for `install_code`. `null` stands for no arguments.
+fn init_arg_bytes(arg: &IDLValue) -> Result<Vec<u8>> {
+    match arg {
+        IDLValue::Blob(bytes) => Ok(bytes.clone()),
+        IDLValue::Null => Ok(IDLArgs::new(&[]).to_bytes()?),
+        _ => Err(anyhow!(
+            "init args need to be an encoded blob, e.g., encode wasm.__init_args(...)"
+        )),
+    }
+}
+/// Build a `canister_install_mode` variant. Only `upgrade` takes options.
+fn install_mode(mode: &str, opts: Option<&IDLValue>) -> IDLValue {
+    let val = match (mode, opts) {
+        ("upgrade", None | Some(IDLValue::Null | IDLValue::None)) => IDLValue::None,
+        ("upgrade", Some(opts @ IDLValue::Opt(_))) => opts.clone(),
+        ("upgrade", Some(opts)) => IDLValue::Opt(Box::new(opts.clone())),
+        _ => IDLValue::Null,
+    };
+    IDLValue::Variant(VariantValue(
+        Box::new(IDLField {
+            id: Label::Named(mode.to_string()),
+            val,
+        }),
+        0,
+    ))
+}
+fn install_code(
+    helper: &MyHelper,
+    id: Principal,
+    wasm: Vec<u8>,
+    arg: Vec<u8>,
+    mode: IDLValue,
+) -> Result<IDLValue> {
+    call_ic(
+        helper,
+        "install_code",
+        vec![
+            ("canister_id", IDLValue::Principal(id)),
+            ("wasm_module", IDLValue::Blob(wasm)),
+            ("arg", IDLValue::Blob(arg)),
+            ("mode", mode),
+            ("sender_canister_version", IDLValue::None),
+        ],
+    )
+}
+/// Call a management canister method with a single record argument.
+fn call_ic(helper: &MyHelper, method: &str, args: Vec<(&str, IDLValue)>) -> Result<IDLValue> {
+    let mut fields: Vec<_> = args
+        .into_iter()
+        .map(|(id, val)| IDLField {
+            id: Label::Named(id.to_string()),
+            val,
+        })
+        .collect();
+    fields.sort_by_key(|f| f.id.get_id());
+    let method = Method {
+        canister: "ic".to_string(),
+        method: method.to_string(),
+    };
+    call_method(helper, &method, IDLArgs::new(&[IDLValue::Record(fields)])).map_err(|e| {
+        if e.to_string().contains("running") {
+            e.context(
+                "The canister must be stopped first, e.g., call ic.stop_canister(record { canister_id = ... })",
+            )
+        } else {
+            e
+        }
+    })
+}
+/// Check the number of arguments against the method signature. Trailing opt, null and reserved
+/// arguments can be omitted, and are filled in with their default values.
+fn check_arity(method: &str, env: &TypeEnv, func: &Function, args: &mut IDLArgs) -> Result<()> {
+    let default = |t: &Type| match env.trace_type(t).map(|t| t.as_ref().clone()) {
+        Ok(TypeInner::Opt(_)) => Some(IDLValue::None),
+        Ok(TypeInner::Null) => Some(IDLValue::Null),
+        Ok(TypeInner::Reserved) => Some(IDLValue::Reserved),
+        _ => None,
+    };
+    let given = args.args.len();
+    let required = func
+        .args
+        .iter()
+        .rposition(|t| default(t).is_none())
+        .map_or(0, |i| i + 1);
+    if given < required || given > func.args.len() {
+        let types: Vec<_> = func.args.iter().map(|t| t.to_string()).collect();
+        return Err(anyhow!(
+            "{method} expects {} arguments of type ({}), but {given} are given",
+            func.args.len(),
+            types.join(", ")
+        ));
+    }
+    for t in &func.args[given..] {
+        args.args.push(default(t).unwrap());
+    }
+    Ok(())
+}
+/// Call a canister method with already evaluated arguments.
+fn call_method(helper: &MyHelper, method: &Method, args: IDLArgs) -> Result<IDLValue> {
+    let info = method.get_info(helper, false)?;
+    let bytes = match &info.signature {
+        Some((env, func)) => args.to_bytes_with_types(env, &func.args)?,
+        None => crate::utils::untyped_args(args, helper).to_bytes()?,
+    };
+    call(
+        helper,
+        &info.canister_id,
+        &method.method,
+        &bytes,
+        &info.signature,
+        &helper.offline,
+    )
+    .map(args_to_value)
+}
+/// Poll the balance of the account every `interval` seconds until Ctrl-C, and return the last balance.
+/// A principal or an ICRC-1 account record is looked up with `icrc1_balance_of`, and an account id
+/// blob or hex text with the `account_balance` method of the legacy ledger.
+fn watch_balance(
+    helper: &MyHelper,
+    ledger: Principal,
+    account: &IDLValue,
+    interval: u32,
+) -> Result<IDLValue> {
+    use console::style;
+    let field = |id: &str, val: IDLValue| IDLField {
+        id: Label::Named(id.to_string()),
+        val,
+    };
+    let (method, arg) = match account {
+        IDLValue::Principal(owner) => {
+            let mut fs = vec![
+                field("owner", IDLValue::Principal(*owner)),
+                field("subaccount", IDLValue::None),
+            ];
+            fs.sort_by_key(|f| f.id.get_id());
+            ("icrc1_balance_of", IDLValue::Record(fs))
+        }
+        IDLValue::Record(fs) if get_field(fs, "owner").is_some() => {
+            ("icrc1_balance_of", account.clone())
+        }
+        IDLValue::Blob(_) => (
+            "account_balance",
+            IDLValue::Record(vec![field("account", account.clone())]),
+        ),
+        IDLValue::Text(hex) => {
+            let id = crate::account_identifier::AccountIdentifier::from_hex(hex)
+                .map_err(|e| anyhow!("{e}"))?;
+            (
+                "account_balance",
+                IDLValue::Record(vec![field("account", IDLValue::Blob(id.to_vec()))]),
+            )
+        }
+        _ => {
+            return Err(anyhow!(
+                "{account} is not a principal, ICRC-1 account or account id"
+            ))
+        }
+    };
+    let method = Method {
+        canister: ledger.to_string(),
+        method: method.to_string(),
+    };
+    println!(
+        "Polling {ledger}.{} every {interval}s. Press Ctrl-C to stop.",
+        method.method
+    );
+    let args = IDLArgs::new(&[arg]);
+    let guard = crate::command::WatchGuard::new();
+    let mut last = IDLValue::Null;
+    loop {
+        let now = crate::utils::format_timestamp(crate::utils::now_nanos()?);
+        match call_method(helper, &method, args.clone()) {
+            Ok(v) => {
+                let balance = match &v {
+                    IDLValue::Record(fs) => get_field(fs, "e8s").cloned().unwrap_or(v),
+                    _ => v,
+                };
+                let shown = crate::utils::pp_value(&balance, helper);
+                match (crate::utils::as_int(&last), crate::utils::as_int(&balance)) {
+                    (Some(prev), Some(n)) if prev != n => {
+                        let delta = n - prev;
+                        let sign = if delta > 0 { "+" } else { "" };
+                        let line = format!("{now} {shown} ({sign}{delta})");
+                        println!("{}", style(line).yellow().bold());
+                    }
+                    _ => println!("{now} {shown}"),
+                }
+                last = balance;
             }
-            let res = helper.env.0.get("_").unwrap_or(&IDLValue::Null).clone();
-            Ok(res)
+            Err(e) => println!("{now} {}", style(format!("Error: {e}")).red()),
+        }
+        let start = std::time::Instant::now();
+        while start.elapsed() < std::time::Duration::from_secs(interval as u64) {
+            if guard.interrupted() {
+                return Ok(last);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
         }
     }
 }
+/// Follow the streaming callbacks of an HTTP response, and concatenate the body chunks.
+fn streaming_body(helper: &MyHelper, response: &IDLValue, max_bytes: usize) -> Result<Vec<u8>> {
+    fn bytes(v: Option<&IDLValue>) -> Result<Vec<u8>> {
+        match v {
+            Some(IDLValue::Blob(b)) => Ok(b.clone()),
+            Some(IDLValue::Vec(vs)) => vs
+                .iter()
+                .map(|v| match v {
+                    IDLValue::Nat8(n) => Ok(*n),
+                    _ => Err(anyhow!("body is not a blob")),
+                })
+                .collect(),
+            _ => Err(anyhow!("response has no body blob")),
+        }
+    }
+    let IDLValue::Record(fs) = response else {
+        return Err(anyhow!("{response} is not an HTTP response"));
+    };
+    let mut body = bytes(get_field(fs, "body"))?;
+    let (callback, mut token) = match get_field(fs, "streaming_strategy") {
+        None | Some(IDLValue::None | IDLValue::Null) => return Ok(body),
+        Some(IDLValue::Opt(strategy)) => match strategy.as_ref() {
+            IDLValue::Variant(VariantValue(f, _)) => match &f.val {
+                IDLValue::Record(fs) => match (get_field(fs, "callback"), get_field(fs, "token")) {
+                    (Some(IDLValue::Func(id, method)), Some(token)) => {
+                        ((*id, method.clone()), token.clone())
+                    }
+                    _ => return Err(anyhow!("streaming strategy needs a callback and a token")),
+                },
+                v => return Err(anyhow!("unknown streaming strategy {v}")),
+            },
+            v => return Err(anyhow!("unknown streaming strategy {v}")),
+        },
+        Some(v) => return Err(anyhow!("unknown streaming strategy {v}")),
+    };
+    let method = Method {
+        canister: callback.0.to_string(),
+        method: callback.1,
+    };
+    let mut seen = std::collections::BTreeSet::new();
+    loop {
+        if !seen.insert(token.to_string()) {
+            return Err(anyhow!(
+                "streaming callback returns a token seen before: {token}"
+            ));
+        }
+        let res = call_method(helper, &method, IDLArgs::new(&[token]))?;
+        let IDLValue::Record(fs) = &res else {
+            return Err(anyhow!(
+                "unexpected reply from the streaming callback: {res}"
+            ));
+        };
+        body.extend(bytes(get_field(fs, "body"))?);
+        if body.len() > max_bytes {
+            return Err(anyhow!("response body exceeds {max_bytes} bytes"));
+        }
+        token = match get_field(fs, "token") {
+            Some(IDLValue::Opt(token)) => token.as_ref().clone(),
+            _ => return Ok(body),
+        };
+    }
+}
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn parallel_calls(
     futures: Vec<impl std::future::Future<Output = anyhow::Result<IDLArgs>>>,
@@ -929,13 +2270,65 @@ async fn call(
     opt_func: &Option<(TypeEnv, Function)>,
     offline: &Option<OfflineOutput>,
 ) -> anyhow::Result<IDLArgs> {
+    let bytes = call_reply(helper, canister_id, method, args, opt_func, offline).await?;
+    let res = if let Some((env, func)) = opt_func {
+        IDLArgs::from_bytes_with_types(&bytes, env, &func.rets)?
+    } else {
+        IDLArgs::from_bytes(&bytes)?
+    };
+    Ok(res)
+}
+#[tokio::main]
+async fn call_raw_reply(
+    helper: &MyHelper,
+    canister_id: &Principal,
+    method: &str,
+    args: &[u8],
+    opt_func: &Option<(TypeEnv, Function)>,
+    offline: &Option<OfflineOutput>,
+) -> anyhow::Result<Vec<u8>> {
+    call_reply(helper, canister_id, method, args, opt_func, offline).await
+}
+/// Send the call and return the undecoded reply bytes. In offline mode, the message is
+/// written out and the reply is an empty argument list.
+async fn call_reply(
+    helper: &MyHelper,
+    canister_id: &Principal,
+    method: &str,
+    args: &[u8],
+    opt_func: &Option<(TypeEnv, Function)>,
+    offline: &Option<OfflineOutput>,
+) -> anyhow::Result<Vec<u8>> {
     use crate::offline::*;
     let agent = &helper.agent;
-    let effective_id = get_effective_canister_id(*canister_id, method, args)?;
     let is_query = opt_func
         .as_ref()
         .map(|(_, f)| f.is_query())
         .unwrap_or(false);
+    if !is_query && helper.current_identity == "anonymous" {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        WARNED.call_once(|| {
+            crate::command::log(
+                helper,
+                crate::command::LogLevel::Warn,
+                "Sending update calls as the anonymous identity. Use the `identity` command to sign calls with your own identity.",
+            )
+        });
+    }
+    let wallet_args;
+    let (canister_id, method, args, forwarded) = match &helper.wallet {
+        Some((wallet, cycles)) if !is_query && wallet != canister_id => {
+            wallet_args = Encode!(&WalletCall128 {
+                canister: *canister_id,
+                method_name: method.to_string(),
+                args: args.to_vec(),
+                cycles: candid::Nat::from(*cycles),
+            })?;
+            (wallet, "wallet_call128", wallet_args.as_slice(), true)
+        }
+        _ => (canister_id, method, args, false),
+    };
+    let effective_id = get_effective_canister_id(*canister_id, method, args)?;
     let bytes = if is_query {
         let mut builder = agent.query(canister_id, method);
         builder = builder
@@ -954,7 +2347,7 @@ async fn call(
             };
             msgs.push(message.clone());
             output_message(serde_json::to_string(&message)?, offline)?;
-            return Ok(IDLArgs::new(&[]));
+            return Ok(IDLArgs::new(&[]).to_bytes()?);
         } else {
             builder.call().await?
         }
@@ -981,15 +2374,47 @@ async fn call(
             };
             msgs.push(message.clone());
             output_message(serde_json::to_string(&message)?, offline)?;
-            return Ok(IDLArgs::new(&[]));
+            return Ok(IDLArgs::new(&[]).to_bytes()?);
         } else {
-            builder.call_and_wait().await?
+            use ic_agent::agent::CallResponse;
+            let signed = builder.sign()?;
+            *helper.last_request_id.borrow_mut() = Some(hex::encode(signed.request_id.as_slice()));
+            match agent
+                .update_signed(effective_id, signed.signed_update)
+                .await?
+            {
+                CallResponse::Response(bytes) => bytes,
+                CallResponse::Poll(request_id) => agent.wait(&request_id, effective_id).await?.0,
+            }
         }
     };
-    let res = if let Some((env, func)) = opt_func {
-        IDLArgs::from_bytes_with_types(&bytes, env, &func.rets)?
+    let bytes = if forwarded {
+        Decode!(&bytes, Result<WalletResult, String>)?
+            .map_err(|e| anyhow!("wallet_call128 failed: {e}"))?
+            .r#return
     } else {
-        IDLArgs::from_bytes(&bytes)?
+        bytes
     };
-    Ok(res)
+    check_response_size(&bytes, helper.max_response_bytes)?;
+    Ok(bytes)
+}
+fn check_response_size(bytes: &[u8], max_bytes: Option<usize>) -> Result<()> {
+    match max_bytes {
+        Some(max) if bytes.len() > max => Err(anyhow!(
+            "response of {} bytes exceeds the limit of {max} bytes set by set_max_response_bytes",
+            bytes.len()
+        )),
+        _ => Ok(()),
+    }
+}
+#[derive(candid::CandidType)]
+struct WalletCall128 {
+    canister: Principal,
+    method_name: String,
+    args: Vec<u8>,
+    cycles: candid::Nat,
+}
+#[derive(candid::CandidType, candid::Deserialize)]
+struct WalletResult {
+    r#return: Vec<u8>,
 }
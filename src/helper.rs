@@ -86,11 +86,35 @@ pub struct MyHelper {
     pub agent_url: String,
     pub agent: Agent,
     pub config: Configs,
+    pub canister_configs: BTreeMap<String, Configs>,
     pub env: Env,
     pub func_env: FuncEnv,
     pub base_path: std::path::PathBuf,
     pub messages: RefCell<Vec<crate::offline::IngressWithStatus>>,
     pub verbose: bool,
+    pub on_error: Option<Vec<crate::command::Command>>,
+    pub defers: Vec<(Vec<crate::command::Command>, Env)>,
+    pub log_level: crate::command::LogLevel,
+    pub total_cost: i64,
+    pub last_request_id: RefCell<Option<String>>,
+    pub last_call_duration: std::cell::Cell<Option<std::time::Duration>>,
+    pub export_file: Option<std::path::PathBuf>,
+    pub pretty: bool,
+    pub blob_display: crate::command::BlobDisplay,
+    pub blob_full: bool,
+    pub number_grouped: bool,
+    pub nat_default: bool,
+    pub history: Vec<String>,
+    pub recording: Option<std::path::PathBuf>,
+    pub wallet: Option<(Principal, u128)>,
+    pub default_canister: Option<String>,
+    pub identity_seed: Option<Vec<u8>>,
+    pub labels: BTreeMap<u32, String>,
+    pub interactive: bool,
+    pub checkpoints: BTreeMap<String, (Env, std::collections::BTreeSet<String>)>,
+    pub consts: std::collections::BTreeSet<String>,
+    pub max_response_bytes: Option<usize>,
+    pub func_args: std::rc::Rc<RefCell<Vec<Vec<IDLValue>>>>,
 }
 
 impl MyHelper {
@@ -102,6 +126,7 @@ impl MyHelper {
             colored_prompt: "".to_owned(),
             validator: MatchingBracketValidator::new(),
             config: "".parse::<Configs>().unwrap(),
+            canister_configs: self.canister_configs.clone(),
             canister_map: self.canister_map.clone(),
             identity_map: self.identity_map.clone(),
             current_identity: self.current_identity.clone(),
@@ -113,6 +138,29 @@ impl MyHelper {
             offline: self.offline.clone(),
             messages: self.messages.clone(),
             verbose: self.verbose,
+            on_error: None,
+            defers: Vec::new(),
+            log_level: self.log_level,
+            total_cost: self.total_cost,
+            export_file: self.export_file.clone(),
+            pretty: self.pretty,
+            blob_display: self.blob_display,
+            blob_full: self.blob_full,
+            number_grouped: self.number_grouped,
+            nat_default: self.nat_default,
+            default_canister: self.default_canister.clone(),
+            identity_seed: self.identity_seed.clone(),
+            labels: self.labels.clone(),
+            interactive: self.interactive,
+            checkpoints: self.checkpoints.clone(),
+            consts: self.consts.clone(),
+            max_response_bytes: self.max_response_bytes,
+            func_args: self.func_args.clone(),
+            history: Vec::new(),
+            recording: None,
+            wallet: self.wallet,
+            last_request_id: RefCell::new(None),
+            last_call_duration: Default::default(),
         }
     }
     pub fn new(
@@ -131,6 +179,7 @@ impl MyHelper {
             identity_map: IdentityMap::default(),
             current_identity: "anonymous".to_owned(),
             config: "".parse::<Configs>().unwrap(),
+            canister_configs: BTreeMap::new(),
             env: Env::default(),
             func_env: FuncEnv::default(),
             base_path: std::env::current_dir().unwrap(),
@@ -139,13 +188,37 @@ impl MyHelper {
             agent_url,
             offline,
             verbose,
+            on_error: None,
+            defers: Vec::new(),
+            log_level: crate::command::LogLevel::Info,
+            total_cost: 0,
+            export_file: None,
+            pretty: true,
+            blob_display: crate::command::BlobDisplay::Candid,
+            blob_full: false,
+            number_grouped: false,
+            nat_default: false,
+            default_canister: None,
+            identity_seed: None,
+            labels: BTreeMap::new(),
+            interactive: false,
+            checkpoints: BTreeMap::new(),
+            consts: Default::default(),
+            max_response_bytes: None,
+            func_args: Default::default(),
+            history: Vec::new(),
+            recording: None,
+            wallet: None,
+            last_request_id: RefCell::new(None),
+            last_call_duration: Default::default(),
         };
         res.fetch_root_key_if_needed().unwrap();
         res.load_prelude().unwrap();
         res
     }
-    fn is_mainnet(&self) -> bool {
-        self.agent_url == "https://icp0.io" || self.agent_url == "https://ic0.app"
+    pub fn is_mainnet(&self) -> bool {
+        let url = self.agent_url.trim_end_matches('/');
+        url == "https://icp0.io" || url == "https://ic0.app"
     }
     fn load_prelude(&mut self) -> anyhow::Result<()> {
         self.identity_map.0.insert(
@@ -421,8 +494,8 @@ fn hint_method(line: &str, pos: usize, helper: &MyHelper) -> Option<String> {
     let (_, given_args, call) = find_lastest_call(line, helper)?;
     let mut map = helper.canister_map.borrow_mut();
     let (env, args) = call.get_func_type(&helper.agent, &mut map)?;
-    let method = match &call {
-        Partial::Call(_, method) => Some(method),
+    let (id, method) = match &call {
+        Partial::Call(id, method) => Some((id, method)),
         _ => None,
     }?;
     let ty = &args[given_args];
@@ -430,7 +503,13 @@ fn hint_method(line: &str, pos: usize, helper: &MyHelper) -> Option<String> {
         method,
         position: Some(ScopePos::Arg),
     };
-    let mut value = random_value(env, ty, helper.config.clone(), scope).ok()?;
+    let config = helper
+        .canister_configs
+        .iter()
+        .find(|(name, _)| str_to_principal(name, helper).ok().as_ref() == Some(id))
+        .map(|(_, config)| config)
+        .unwrap_or(&helper.config);
+    let mut value = random_value(env, ty, config.clone(), scope).ok()?;
     if given_args == args.len() - 1 {
         value.push(')');
     }
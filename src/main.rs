@@ -10,6 +10,7 @@ mod exp;
 mod grammar;
 mod helper;
 mod offline;
+mod pocketic;
 mod profiling;
 mod selector;
 mod token;
@@ -59,12 +60,22 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
         .with_max_polling_time(std::time::Duration::from_secs(60 * 10))
         .build()?;
 
-    println!("Canister REPL");
+    println!("Canister REPL, {}", utils::version_info());
     let config = rustyline::Config::builder()
         .history_ignore_space(true)
         .completion_type(CompletionType::List)
         .build();
-    let h = MyHelper::new(agent, url.to_string(), offline, opts.verbose);
+    let mut h = MyHelper::new(agent, url.to_string(), offline, opts.verbose);
+    h.export_file = opts.export_file.map(std::path::PathBuf::from);
+    h.max_response_bytes = opts.max_response_bytes;
+    if let Some(seed) = opts
+        .seed_identity
+        .or_else(|| std::env::var("IC_REPL_SEED_IDENTITY").ok())
+    {
+        use anyhow::Context;
+        h.identity_seed =
+            Some(hex::decode(seed.trim()).context("--seed-identity needs to be a hex string")?);
+    }
     if let Some(file) = opts.send {
         use crate::offline::{send_messages, Messages};
         let json = std::fs::read_to_string(file)?;
@@ -76,14 +87,28 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
     rl.set_helper(Some(h));
     let _ = rl.load_history("./.history");
     if let Some(file) = opts.config {
-        let config = std::fs::read_to_string(file)?;
-        rl.helper_mut().unwrap().config = config.parse::<candid_parser::configs::Configs>()?;
+        use anyhow::Context;
+        let config =
+            std::fs::read_to_string(&file).with_context(|| format!("Cannot read {file}"))?;
+        rl.helper_mut().unwrap().config = config
+            .parse::<candid_parser::configs::Configs>()
+            .with_context(|| format!("Cannot parse {file}"))?;
     }
 
     let enter_repl = opts.script.is_none() || opts.interactive;
     if let Some(file) = opts.script {
         let cmd = Command::Load(exp::Exp::Text(file));
         let helper = rl.helper_mut().unwrap();
+        let argv = opts
+            .script_args
+            .into_iter()
+            .chain(opts.extra_args.iter().cloned())
+            .map(candid::IDLValue::Text)
+            .collect();
+        helper
+            .env
+            .0
+            .insert("argv".to_string(), candid::IDLValue::Vec(argv));
         cmd.run(helper)?;
         if helper.func_env.0.contains_key("__main") {
             let mut args = Vec::new();
@@ -91,11 +116,22 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
                 let v = candid_parser::parse_idl_value(&arg).unwrap_or(candid::IDLValue::Text(arg));
                 args.push(v);
             }
-            exp::apply_func(helper, "__main", args)?;
+            use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+            let res = catch_unwind(AssertUnwindSafe(|| exp::apply_func(helper, "__main", args)));
+            if !matches!(res, Ok(Ok(_))) {
+                command::run_on_error(helper);
+            }
+            match res {
+                Ok(res) => {
+                    res?;
+                }
+                Err(panic) => resume_unwind(panic),
+            }
         }
     }
     if enter_repl {
         rl.helper_mut().unwrap().verbose = true;
+        rl.helper_mut().unwrap().interactive = true;
         let mut count = 1;
         loop {
             let identity = &rl.helper().unwrap().current_identity;
@@ -108,7 +144,13 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
                     rl.add_history_entry(&line)?;
                     unwrap(pretty_parse::<Command>("stdin", &line), |cmd| {
                         let helper = rl.helper_mut().unwrap();
-                        unwrap(cmd.run(helper), |_| {});
+                        if !matches!(cmd, Command::History | Command::Replay(_)) {
+                            helper.history.push(line.clone());
+                        }
+                        let recorded = cmd.clone();
+                        unwrap(cmd.run(helper), |_| {
+                            unwrap(command::record_command(helper, &recorded, &line), |_| {});
+                        });
                     });
                 }
                 Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
@@ -120,6 +162,7 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
             count += 1;
         }
         rl.save_history("./.history")?;
+        command::run_defers(rl.helper_mut().unwrap(), 0)?;
     }
     if opts.offline {
         let helper = rl.helper().unwrap();
@@ -146,10 +189,13 @@ struct Opts {
     /// Offline URL embeded in the QR code, only used in ascii or png format. Default value: "https://qhmh2-niaaa-aaaab-qadta-cai.raw.ic0.app/?msg="
     url: Option<String>,
     #[clap(short, long)]
-    /// Specifies config file for Candid random value generation
+    /// Specifies config file for Candid random value generation, loaded before the script runs. The `config` command overrides it
     config: Option<String>,
     /// ic-repl script file
     script: Option<String>,
+    #[clap(requires("script"))]
+    /// Arguments passed to the script as the `argv` variable, e.g., when running the script with a shebang line
+    script_args: Vec<String>,
     #[clap(short, long, requires("script"))]
     /// Enter repl once the script is finished
     interactive: bool,
@@ -159,6 +205,16 @@ struct Opts {
     #[clap(short, long)]
     /// Run script in verbose mode. Non-verbose mode will only output text values.
     verbose: bool,
+    #[clap(long)]
+    /// Hex seed to derive the keys of new identities created by `identity <id>` deterministically,
+    /// so that their principals are stable across runs. Defaults to the IC_REPL_SEED_IDENTITY environment variable
+    seed_identity: Option<String>,
+    #[clap(long)]
+    /// Fail calls whose reply exceeds this number of bytes. The `set_max_response_bytes` command overrides it
+    max_response_bytes: Option<usize>,
+    #[clap(long)]
+    /// Append variables from the export command to this file, which can be sourced by the shell
+    export_file: Option<String>,
     #[clap(last = true)]
     /// Extra arguments passed to __main function when running a script
     extra_args: Vec<String>,
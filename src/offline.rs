@@ -5,6 +5,7 @@ use candid::Principal;
 use candid::{types::Function, IDLArgs, TypeEnv};
 use ic_agent::{agent::CallResponse, Agent};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, Ordering};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Ingress {
@@ -26,7 +27,7 @@ pub struct IngressWithStatus {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Messages(Vec<IngressWithStatus>);
 
-static mut PNG_COUNTER: u32 = 0;
+static PNG_COUNTER: AtomicU32 = AtomicU32::new(0);
 
 impl Ingress {
     pub fn parse(&self) -> Result<(Principal, Principal, String, Vec<u8>)> {
@@ -92,10 +93,8 @@ pub fn output_message(json: String, format: &OfflineOutput) -> Result<()> {
                 }
                 OfflineOutput::Png(_) | OfflineOutput::PngNoUrl => {
                     let img = code.render::<image::Luma<u8>>().build();
-                    let filename = unsafe {
-                        PNG_COUNTER += 1;
-                        format!("msg{PNG_COUNTER}.png")
-                    };
+                    let counter = PNG_COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
+                    let filename = format!("msg{counter}.png");
                     img.save(&filename)?;
                     println!("QR code saved to {filename}");
                 }
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct RawTime {
+    nanos_since_epoch: u64,
+}
+
+/// Return the instance URL if the replica URL points to a PocketIC instance, e.g., `http://localhost:8080/instances/0/`.
+pub fn instance_url(url: &str) -> Option<String> {
+    let re = regex::Regex::new(r"^(https?://[^/]+/instances/\d+)/?$").unwrap();
+    re.captures(url).map(|c| c[1].to_string())
+}
+
+pub fn require_instance(url: &str, cmd: &str) -> Result<String> {
+    instance_url(url).ok_or_else(|| {
+        anyhow!("{cmd} needs a PocketIC instance URL, e.g., http://localhost:<port>/instances/<id>/, but found {url}")
+    })
+}
+
+async fn check(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        Ok(res)
+    } else {
+        let body = res.text().await.unwrap_or_default();
+        Err(anyhow!("PocketIC returns {status}: {body}"))
+    }
+}
+
+/// Advance the time of the PocketIC instance, and return the new time in nanoseconds since epoch.
+#[tokio::main]
+pub async fn advance_time(url: &str, duration: std::time::Duration) -> Result<u64> {
+    let instance = require_instance(url, "advance_time")?;
+    let client = reqwest::Client::new();
+    let res = client
+        .get(format!("{instance}/read/get_time"))
+        .send()
+        .await
+        .context("Cannot get the time of the PocketIC instance")?;
+    let now: RawTime = check(res).await?.json().await?;
+    let time = RawTime {
+        nanos_since_epoch: now.nanos_since_epoch + duration.as_nanos() as u64,
+    };
+    let res = client
+        .post(format!("{instance}/update/set_time"))
+        .json(&time)
+        .send()
+        .await
+        .context("Cannot set the time of the PocketIC instance")?;
+    check(res).await?;
+    Ok(time.nanos_since_epoch)
+}
+
+/// Execute `n` rounds on all subnets of the PocketIC instance.
+#[tokio::main]
+pub async fn tick(url: &str, n: usize) -> Result<()> {
+    let instance = require_instance(url, "tick")?;
+    let client = reqwest::Client::new();
+    for _ in 0..n {
+        let res = client
+            .post(format!("{instance}/update/tick"))
+            .json(&serde_json::json!({}))
+            .send()
+            .await
+            .context("Cannot execute a round on the PocketIC instance")?;
+        check(res).await?;
+    }
+    Ok(())
+}
@@ -149,6 +149,48 @@ pub fn project(helper: &MyHelper, value: IDLValue, path: Vec<Selector>) -> Resul
     Ok(result)
 }
 
+/// Walk a path like `a.b[0]?.c` in `value`. Numeric field names select tuple fields.
+/// Errors report the prefix of the path that fails.
+pub fn select(helper: &MyHelper, value: IDLValue, path: &str) -> Result<IDLValue> {
+    let mut result = value;
+    let mut rest = path;
+    while !rest.is_empty() {
+        let (head, tail) = if let Some(tail) = rest.strip_prefix('?') {
+            (Selector::Option, tail)
+        } else if let Some(tail) = rest.strip_prefix('[') {
+            let end = tail
+                .find(']')
+                .ok_or_else(|| anyhow!("missing ] in path {path}"))?;
+            let idx = tail[..end].trim();
+            if idx.is_empty() || !idx.bytes().all(|c| c.is_ascii_digit()) {
+                return Err(anyhow!("{idx:?} is not an index in path {path}"));
+            }
+            (
+                Selector::Index(Exp::Number(idx.to_string())),
+                &tail[end + 1..],
+            )
+        } else {
+            let name = rest.strip_prefix('.').unwrap_or(rest);
+            let end = name.find(['.', '[', '?']).unwrap_or(name.len());
+            let field = &name[..end];
+            if field.is_empty() {
+                return Err(anyhow!("empty field name in path {path}"));
+            }
+            let head = if field.bytes().all(|c| c.is_ascii_digit()) {
+                Selector::Index(Exp::Number(field.to_string()))
+            } else {
+                Selector::Field(field.to_string())
+            };
+            (head, &name[end..])
+        };
+        let prefix = &path[..path.len() - tail.len()];
+        result = project(helper, result, vec![head])
+            .map_err(|e| anyhow!("select fails at {prefix}: {e}"))?;
+        rest = tail;
+    }
+    Ok(result)
+}
+
 fn from_fields(fs: Vec<IDLField>) -> Vec<IDLValue> {
     fs.into_iter()
         .map(|f| {
@@ -63,6 +63,8 @@ pub enum Token {
     Fail,
     #[token("call")]
     Call,
+    #[token("call_raw_reply")]
+    CallRawReply,
     #[token("par_call")]
     ParCall,
     #[token("encode")]
@@ -79,6 +81,12 @@ pub enum Token {
     Assert,
     #[token("identity")]
     Identity,
+    #[token("load_identities")]
+    LoadIdentities,
+    #[token("whoami")]
+    Whoami,
+    #[token("agent_info")]
+    AgentInfo,
     #[token("load")]
     Load,
     #[token("function")]
@@ -89,12 +97,54 @@ pub enum Token {
     If,
     #[token("else")]
     Else,
+    #[token("on_error")]
+    OnError,
+    #[token("advance_time")]
+    AdvanceTime,
+    #[token("set_backend")]
+    SetBackend,
+    #[token("#echo")]
+    Echo,
+    #[token("log_info")]
+    LogInfo,
+    #[token("log_warn")]
+    LogWarn,
+    #[token("log_error")]
+    LogError,
+    #[token("set_log_level")]
+    SetLogLevel,
+    #[token("set_verbose")]
+    SetVerbose,
+    #[token("set_candid_option")]
+    SetCandidOption,
+    #[token("set_pretty")]
+    SetPretty,
+    #[token("set_blob_display")]
+    SetBlobDisplay,
+    #[token("set_number_format")]
+    SetNumberFormat,
+    #[token("set_int_default")]
+    SetIntDefault,
+    #[token("set_max_response_bytes")]
+    SetMaxResponseBytes,
+    #[token("set_wallet")]
+    SetWallet,
+    #[token("clear_wallet")]
+    ClearWallet,
+    #[token("|")]
+    Pipe,
     #[token("==")]
     TestEqual,
     #[token("~=")]
     SubEqual,
     #[token("!=")]
     NotEqual,
+    #[token("assert_count")]
+    AssertCount,
+    #[token("assert_type")]
+    AssertType,
+    #[token("assert_eventually")]
+    AssertEventually,
     #[token("principal")]
     Principal,
     #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
@@ -166,11 +216,6 @@ impl<'input> Tokenizer<'input> {
 
 pub type Span = std::ops::Range<usize>;
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Spanned<T> {
-    pub span: Span,
-    pub value: T,
-}
-#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct LexicalError {
     pub err: String,
     pub span: Span,
@@ -5,11 +5,42 @@ use candid::types::value::{IDLArgs, IDLField, IDLValue};
 use candid::types::{Label, Type, TypeInner};
 use candid::{Principal, TypeEnv};
 use candid_parser::configs::Configs;
+use ic_agent::identity::{Delegation, SignedDelegation};
 use ic_agent::Agent;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
-pub fn stringify(v: &IDLValue) -> anyhow::Result<Cow<str>> {
+/// Sends unsigned requests with a canister id as the sender. Only PocketIC instances, which skip signature
+/// verification, accept such requests, which allows simulating inter-canister calls.
+pub struct CanisterIdentity(pub Principal);
+impl ic_agent::Identity for CanisterIdentity {
+    fn sender(&self) -> Result<Principal, String> {
+        Ok(self.0)
+    }
+    fn public_key(&self) -> Option<Vec<u8>> {
+        None
+    }
+    fn sign(&self, _: &ic_agent::agent::EnvelopeContent) -> Result<ic_agent::Signature, String> {
+        Ok(ic_agent::Signature {
+            public_key: None,
+            signature: None,
+            delegations: None,
+        })
+    }
+}
+
+pub fn version_info() -> String {
+    format!(
+        "ic-repl {} (candid {}, candid_parser {}, ic-agent {})",
+        env!("CARGO_PKG_VERSION"),
+        env!("CANDID_VERSION"),
+        env!("CANDID_PARSER_VERSION"),
+        env!("IC_AGENT_VERSION")
+    )
+}
+
+pub fn stringify(v: &IDLValue) -> anyhow::Result<Cow<'_, str>> {
     Ok(match v {
         IDLValue::Text(str) => Cow::Borrowed(str),
         IDLValue::Number(_)
@@ -39,6 +70,482 @@ pub fn stringify(v: &IDLValue) -> anyhow::Result<Cow<str>> {
     })
 }
 
+/// Substitute each `{}` placeholder in `template` with the next argument, rendered as in `stringify`.
+/// `{{` and `}}` produce literal braces.
+pub fn format_template(template: &str, args: &[IDLValue]) -> Result<String> {
+    let mut res = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                res.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                let arg = args
+                    .next()
+                    .ok_or_else(|| anyhow!("format has more placeholders than arguments"))?;
+                res.push_str(&stringify(arg)?);
+            }
+            ('{' | '}', _) => {
+                return Err(anyhow!(
+                    "unmatched {c} in format template, use {c}{c} for a literal brace"
+                ))
+            }
+            _ => res.push(c),
+        }
+    }
+    if args.next().is_some() {
+        return Err(anyhow!("format has more arguments than placeholders"));
+    }
+    Ok(res)
+}
+
+/// Render the value over multiple indented lines when `helper.pretty` is set, otherwise on a single line.
+/// Blobs are rendered according to `helper.blob_display`.
+pub fn pp_value(v: &IDLValue, helper: &MyHelper) -> String {
+    use crate::command::BlobDisplay;
+    if let Some((func, _)) = crate::exp::as_func(helper, v) {
+        return format!("function {func}");
+    }
+    let rendered;
+    let v = if helper.blob_display == BlobDisplay::Candid {
+        v
+    } else {
+        rendered = render_blobs(v.clone(), helper.blob_display, helper.blob_full);
+        &rendered
+    };
+    let resolved;
+    let v = if helper.labels.is_empty() {
+        v
+    } else {
+        resolved = resolve_labels(v.clone(), &helper.labels);
+        &resolved
+    };
+    let grouped;
+    let v = if helper.number_grouped {
+        grouped = group_numbers(v.clone());
+        &grouped
+    } else {
+        v
+    };
+    if helper.pretty {
+        v.to_string()
+    } else {
+        // Same depth limit as the Display implementation of IDLValue
+        candid::pretty::candid::value::pp_value(10, v)
+            .pretty(usize::MAX)
+            .to_string()
+    }
+}
+
+/// Replace blobs with their text rendering. Unless `full` is set, blobs longer than 64 bytes are truncated.
+fn render_blobs(v: IDLValue, mode: crate::command::BlobDisplay, full: bool) -> IDLValue {
+    use crate::command::BlobDisplay;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    const MAX_BYTES: usize = 64;
+    match v {
+        IDLValue::Blob(blob) => {
+            let bytes = if full || blob.len() <= MAX_BYTES {
+                &blob[..]
+            } else {
+                &blob[..MAX_BYTES]
+            };
+            let mut res = match mode {
+                BlobDisplay::Hex => hex::encode(bytes),
+                BlobDisplay::Base64 => STANDARD.encode(bytes),
+                BlobDisplay::Text => String::from_utf8_lossy(bytes).into_owned(),
+                BlobDisplay::Candid => unreachable!(),
+            };
+            if bytes.len() < blob.len() {
+                res += &format!("...({} bytes)", blob.len());
+            }
+            IDLValue::Text(res)
+        }
+        IDLValue::Vec(vs) if matches!(vs.first(), Some(IDLValue::Nat8(_))) => {
+            let blob = vs
+                .into_iter()
+                .filter_map(|v| match v {
+                    IDLValue::Nat8(n) => Some(n),
+                    _ => None,
+                })
+                .collect();
+            render_blobs(IDLValue::Blob(blob), mode, full)
+        }
+        IDLValue::Vec(vs) => IDLValue::Vec(
+            vs.into_iter()
+                .map(|v| render_blobs(v, mode, full))
+                .collect(),
+        ),
+        IDLValue::Opt(v) => IDLValue::Opt(Box::new(render_blobs(*v, mode, full))),
+        IDLValue::Record(fs) => IDLValue::Record(
+            fs.into_iter()
+                .map(|f| IDLField {
+                    id: f.id,
+                    val: render_blobs(f.val, mode, full),
+                })
+                .collect(),
+        ),
+        IDLValue::Variant(mut v) => {
+            v.0.val = render_blobs(v.0.val, mode, full);
+            IDLValue::Variant(v)
+        }
+        v => v,
+    }
+}
+
+/// Canister ids are an 8-byte big-endian index followed by `0x01 0x01`. Returns the principal with index `+ offset`.
+pub fn offset_canister_id(id: &Principal, offset: u64) -> Result<Principal> {
+    let bytes = id.as_slice();
+    if bytes.len() != 10 || bytes[8..] != [1, 1] {
+        return Err(anyhow!("{id} is not a canister id"));
+    }
+    let index = u64::from_be_bytes(bytes[..8].try_into()?)
+        .checked_add(offset)
+        .ok_or_else(|| anyhow!("canister id overflows after {id}"))?;
+    let mut res = index.to_be_bytes().to_vec();
+    res.extend([1, 1]);
+    Ok(Principal::from_slice(&res))
+}
+
+/// Convert JSON to a candid value. Integers become untyped numbers, and objects become records.
+pub fn json_to_value(json: serde_json::Value) -> IDLValue {
+    use serde_json::Value;
+    match json {
+        Value::Null => IDLValue::Null,
+        Value::Bool(b) => IDLValue::Bool(b),
+        Value::Number(n) if n.is_i64() || n.is_u64() => IDLValue::Number(n.to_string()),
+        Value::Number(n) => IDLValue::Float64(n.as_f64().unwrap()),
+        Value::String(s) => IDLValue::Text(s),
+        Value::Array(vs) => IDLValue::Vec(vs.into_iter().map(json_to_value).collect()),
+        Value::Object(map) => {
+            let mut fields: Vec<_> = map
+                .into_iter()
+                .map(|(k, v)| IDLField {
+                    id: Label::Named(k),
+                    val: json_to_value(v),
+                })
+                .collect();
+            fields.sort_by_key(|f| f.id.get_id());
+            IDLValue::Record(fields)
+        }
+    }
+}
+
+/// Parse CSV into a vector of records, using the header row as field names. Integer cells become untyped numbers,
+/// and other cells become text.
+pub fn csv_to_value(csv: &str) -> Result<IDLValue> {
+    let rows = parse_csv(csv)?;
+    let Some(((_, header), rows)) = rows.split_first() else {
+        return Err(anyhow!("CSV has no header row"));
+    };
+    let mut ids: Vec<_> = header
+        .iter()
+        .map(|k| Label::Named(k.clone()).get_id())
+        .collect();
+    ids.sort();
+    if ids.windows(2).any(|w| w[0] == w[1]) {
+        return Err(anyhow!("line 1: duplicate column names"));
+    }
+    let mut res = Vec::with_capacity(rows.len());
+    for (line, row) in rows {
+        if row.len() != header.len() {
+            return Err(anyhow!(
+                "line {line}: expects {} columns, but found {}",
+                header.len(),
+                row.len()
+            ));
+        }
+        let mut fields: Vec<_> = header
+            .iter()
+            .zip(row)
+            .map(|(k, v)| IDLField {
+                id: Label::Named(k.clone()),
+                val: if v.parse::<i128>().is_ok() {
+                    IDLValue::Number(v.clone())
+                } else {
+                    IDLValue::Text(v.clone())
+                },
+            })
+            .collect();
+        fields.sort_by_key(|f| f.id.get_id());
+        res.push(IDLValue::Record(fields));
+    }
+    Ok(IDLValue::Vec(res))
+}
+
+/// Split CSV into rows of cells, following RFC 4180 quoting. Returns the starting line number of each row.
+fn parse_csv(csv: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut cell = String::new();
+    let (mut line, mut column) = (1, 0);
+    let mut row_line = 1;
+    let mut in_quotes = false;
+    let mut quote_pos = (0, 0);
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        column += 1;
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    column += 1;
+                    cell.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if cell.is_empty() => {
+                in_quotes = true;
+                quote_pos = (line, column);
+            }
+            '"' => return Err(anyhow!("line {line}, column {column}: unexpected quote")),
+            ',' if !in_quotes => row.push(std::mem::take(&mut cell)),
+            '\r' if !in_quotes && chars.peek() == Some(&'\n') => (),
+            '\n' if !in_quotes => {
+                row.push(std::mem::take(&mut cell));
+                rows.push((row_line, std::mem::take(&mut row)));
+                line += 1;
+                column = 0;
+                row_line = line;
+            }
+            '\n' => {
+                cell.push(c);
+                line += 1;
+                column = 0;
+            }
+            c => cell.push(c),
+        }
+    }
+    if in_quotes {
+        let (line, column) = quote_pos;
+        return Err(anyhow!("line {line}, column {column}: unclosed quote"));
+    }
+    if !cell.is_empty() || !row.is_empty() {
+        row.push(cell);
+        rows.push((row_line, row));
+    }
+    // skip empty lines
+    rows.retain(|(_, row)| !(row.len() == 1 && row[0].is_empty()));
+    Ok(rows)
+}
+
+/// Insert `,` as thousands separators into a decimal integer string, e.g., `-1234567` becomes `-1,234,567`.
+pub fn group_digits(n: &str) -> String {
+    let (sign, digits) = match n.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", n),
+    };
+    let digits: Vec<char> = digits.chars().filter(|c| *c != '_').collect();
+    let mut res = String::from(sign);
+    for (i, c) in digits.iter().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            res.push(',');
+        }
+        res.push(*c);
+    }
+    res
+}
+
+/// Scale a decimal amount, e.g., `1.5`, by `10^decimals` into a nat. Fails if precision would be lost.
+pub fn scale_tokens(amount: &str, decimals: u32) -> Result<candid::Nat> {
+    let amount = amount.replace('_', "");
+    if amount.contains(['e', 'E']) {
+        return Err(anyhow!("{amount} is not a decimal number"));
+    }
+    let (int, frac) = amount.split_once('.').unwrap_or((&amount, ""));
+    let frac = frac.trim_end_matches('0');
+    if frac.len() > decimals as usize {
+        return Err(anyhow!(
+            "{amount} has more than {decimals} decimal places, and cannot be represented exactly"
+        ));
+    }
+    let digits = format!("{int}{frac}{}", "0".repeat(decimals as usize - frac.len()));
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("{amount} is not a non-negative decimal number"));
+    }
+    Ok(digits.parse::<candid::Nat>()?)
+}
+
+/// Render a nat scaled by `10^decimals` as a decimal string, e.g., `150_000_000` with 8 decimals becomes `1.5`.
+pub fn display_tokens(n: &candid::Nat, decimals: u32) -> String {
+    let digits = n.0.to_string();
+    let decimals = decimals as usize;
+    let digits = format!("{digits:0>width$}", width = decimals + 1);
+    let (int, frac) = digits.split_at(digits.len() - decimals);
+    let frac = frac.trim_end_matches('0');
+    if frac.is_empty() {
+        int.to_string()
+    } else {
+        format!("{int}.{frac}")
+    }
+}
+
+pub fn as_int(v: &IDLValue) -> Option<candid::Int> {
+    let n = match v {
+        IDLValue::Number(n) => n.replace('_', ""),
+        IDLValue::Nat(n) => n.0.to_string(),
+        IDLValue::Int(n) => n.0.to_string(),
+        IDLValue::Nat8(n) => n.to_string(),
+        IDLValue::Nat16(n) => n.to_string(),
+        IDLValue::Nat32(n) => n.to_string(),
+        IDLValue::Nat64(n) => n.to_string(),
+        IDLValue::Int8(n) => n.to_string(),
+        IDLValue::Int16(n) => n.to_string(),
+        IDLValue::Int32(n) => n.to_string(),
+        IDLValue::Int64(n) => n.to_string(),
+        _ => return None,
+    };
+    n.parse::<candid::Int>().ok()
+}
+
+pub fn format_integer(v: &IDLValue) -> Option<String> {
+    Some(match v {
+        IDLValue::Number(n) if !n.contains(['.', 'e', 'E']) => group_digits(n),
+        IDLValue::Nat(n) => group_digits(&n.0.to_string()),
+        IDLValue::Int(n) => group_digits(&n.0.to_string()),
+        IDLValue::Nat8(n) => group_digits(&n.to_string()),
+        IDLValue::Nat16(n) => group_digits(&n.to_string()),
+        IDLValue::Nat32(n) => group_digits(&n.to_string()),
+        IDLValue::Nat64(n) => group_digits(&n.to_string()),
+        IDLValue::Int8(n) => group_digits(&n.to_string()),
+        IDLValue::Int16(n) => group_digits(&n.to_string()),
+        IDLValue::Int32(n) => group_digits(&n.to_string()),
+        IDLValue::Int64(n) => group_digits(&n.to_string()),
+        _ => return None,
+    })
+}
+
+/// Replace integers with their comma grouped rendering. Blobs are kept intact.
+fn group_numbers(v: IDLValue) -> IDLValue {
+    if let Some(n) = format_integer(&v) {
+        return IDLValue::Number(n);
+    }
+    match v {
+        IDLValue::Vec(vs) if matches!(vs.first(), Some(IDLValue::Nat8(_))) => IDLValue::Vec(vs),
+        IDLValue::Vec(vs) => IDLValue::Vec(vs.into_iter().map(group_numbers).collect()),
+        IDLValue::Opt(v) => IDLValue::Opt(Box::new(group_numbers(*v))),
+        IDLValue::Record(fs) => IDLValue::Record(
+            fs.into_iter()
+                .map(|f| IDLField {
+                    id: f.id,
+                    val: group_numbers(f.val),
+                })
+                .collect(),
+        ),
+        IDLValue::Variant(mut v) => {
+            v.0.val = group_numbers(v.0.val);
+            IDLValue::Variant(v)
+        }
+        v => v,
+    }
+}
+
+/// Replace hashed field ids with the matching names in `labels`, which maps a label hash to its name.
+pub fn resolve_labels(v: IDLValue, labels: &BTreeMap<u32, String>) -> IDLValue {
+    let resolve = |id: Label| match id {
+        Label::Id(n) => match labels.get(&n) {
+            Some(name) => Label::Named(name.clone()),
+            None => Label::Id(n),
+        },
+        id => id,
+    };
+    match v {
+        IDLValue::Vec(vs) => {
+            IDLValue::Vec(vs.into_iter().map(|v| resolve_labels(v, labels)).collect())
+        }
+        IDLValue::Opt(v) => IDLValue::Opt(Box::new(resolve_labels(*v, labels))),
+        IDLValue::Record(fs) => IDLValue::Record(
+            fs.into_iter()
+                .map(|f| IDLField {
+                    id: resolve(f.id),
+                    val: resolve_labels(f.val, labels),
+                })
+                .collect(),
+        ),
+        IDLValue::Variant(mut v) => {
+            v.0.id = resolve(v.0.id);
+            v.0.val = resolve_labels(v.0.val, labels);
+            IDLValue::Variant(v)
+        }
+        v => v,
+    }
+}
+
+/// Merge `overrides` into the record `base`. Fields in `overrides` take precedence, and nested records
+/// are merged recursively. It is an error to override a field with a value of a different type.
+pub fn merge_records(base: IDLValue, overrides: IDLValue, path: &str) -> Result<IDLValue> {
+    match (base, overrides) {
+        (IDLValue::Record(mut fs), IDLValue::Record(overrides)) => {
+            for o in overrides {
+                match fs.iter_mut().find(|f| f.id == o.id) {
+                    Some(f) => {
+                        let path = format!("{path}.{}", o.id);
+                        let val = std::mem::replace(&mut f.val, IDLValue::Null);
+                        f.val = merge_records(val, o.val, &path)?;
+                    }
+                    None => fs.push(o),
+                }
+            }
+            fs.sort_by_key(|f| f.id.get_id());
+            Ok(IDLValue::Record(fs))
+        }
+        (base, o) => {
+            let compatible = base.value_ty() == o.value_ty()
+                || [&base, &o].iter().any(|v| {
+                    matches!(
+                        v,
+                        IDLValue::Number(_) | IDLValue::Null | IDLValue::None | IDLValue::Opt(_)
+                    )
+                });
+            if !compatible {
+                return Err(anyhow!(
+                    "cannot override {path} of type {} with {o}",
+                    base.value_ty()
+                ));
+            }
+            Ok(o)
+        }
+    }
+}
+
+/// Annotate untyped number literals as nat, so that they encode as `nat` instead of the default `int`.
+pub fn nat_default(v: IDLValue) -> IDLValue {
+    match v {
+        IDLValue::Number(n) if !n.starts_with('-') => match n.parse::<candid::Nat>() {
+            Ok(n) => IDLValue::Nat(n),
+            Err(_) => IDLValue::Number(n),
+        },
+        IDLValue::Vec(vs) => IDLValue::Vec(vs.into_iter().map(nat_default).collect()),
+        IDLValue::Opt(v) => IDLValue::Opt(Box::new(nat_default(*v))),
+        IDLValue::Record(fs) => IDLValue::Record(
+            fs.into_iter()
+                .map(|f| IDLField {
+                    id: f.id,
+                    val: nat_default(f.val),
+                })
+                .collect(),
+        ),
+        IDLValue::Variant(mut v) => {
+            v.0.val = nat_default(v.0.val);
+            IDLValue::Variant(v)
+        }
+        v => v,
+    }
+}
+
+/// Apply the `set_int_default` setting to arguments encoded without type information.
+pub fn untyped_args(args: IDLArgs, helper: &MyHelper) -> IDLArgs {
+    if helper.nat_default {
+        IDLArgs::new(&args.args.into_iter().map(nat_default).collect::<Vec<_>>())
+    } else {
+        args
+    }
+}
+
 fn num_cast_helper(v: IDLValue, truncate_float: bool) -> Result<String> {
     Ok(match v {
         IDLValue::Number(n) => n,
@@ -145,6 +652,167 @@ pub fn cast_type(v: IDLValue, ty: &Type) -> Result<IDLValue> {
     })
 }
 
+/// Structural equality, where a service is considered equal to the principal with the same id.
+pub fn ref_eq(v1: &IDLValue, v2: &IDLValue) -> bool {
+    fn normalize(v: IDLValue) -> IDLValue {
+        match v {
+            IDLValue::Service(id) => IDLValue::Principal(id),
+            IDLValue::Opt(v) => IDLValue::Opt(Box::new(normalize(*v))),
+            IDLValue::Vec(vs) => IDLValue::Vec(vs.into_iter().map(normalize).collect()),
+            IDLValue::Record(fs) => IDLValue::Record(
+                fs.into_iter()
+                    .map(|f| IDLField {
+                        id: f.id,
+                        val: normalize(f.val),
+                    })
+                    .collect(),
+            ),
+            IDLValue::Variant(mut v) => {
+                v.0.val = normalize(v.0.val);
+                IDLValue::Variant(v)
+            }
+            v => v,
+        }
+    }
+    v1 == v2 || normalize(v1.clone()) == normalize(v2.clone())
+}
+
+/// Like `ref_eq`, but also annotates untyped values, e.g., number literals, with the type of the other side.
+pub fn typed_eq(v1: &IDLValue, v2: &IDLValue) -> bool {
+    let env = TypeEnv::new();
+    ref_eq(v1, v2)
+        || matches!(v1.annotate_type(true, &env, &v2.value_ty()), Ok(v1) if ref_eq(&v1, v2))
+        || matches!(v2.annotate_type(true, &env, &v1.value_ty()), Ok(v2) if ref_eq(v1, &v2))
+}
+
+/// Check if `v1` and `v2` are equal within a relative `epsilon`. Returns None if neither value is a float.
+pub fn approx_eq(v1: &IDLValue, v2: &IDLValue, epsilon: f64) -> Result<Option<bool>> {
+    if !matches!(v1, IDLValue::Float32(_) | IDLValue::Float64(_))
+        && !matches!(v2, IDLValue::Float32(_) | IDLValue::Float64(_))
+    {
+        return Ok(None);
+    }
+    let IDLValue::Float64(v1) = cast_type(v1.clone(), &TypeInner::Float64.into())? else {
+        panic!()
+    };
+    let IDLValue::Float64(v2) = cast_type(v2.clone(), &TypeInner::Float64.into())? else {
+        panic!()
+    };
+    let scale = 1f64.max(v1.abs()).max(v2.abs());
+    Ok(Some(v1 == v2 || (v1 - v2).abs() <= epsilon * scale))
+}
+
+/// Arithmetic and comparison of two numbers. If one of the arguments is a float, the computation is done in float64;
+/// otherwise, the computation is done in int and the result is an untyped number.
+pub fn arith(op: &str, v1: &IDLValue, v2: &IDLValue) -> Result<IDLValue> {
+    Ok(match (v1, v2) {
+        (IDLValue::Float32(_) | IDLValue::Float64(_), _)
+        | (_, IDLValue::Float32(_) | IDLValue::Float64(_)) => {
+            let IDLValue::Float64(v1) = cast_type(v1.clone(), &TypeInner::Float64.into())? else {
+                panic!()
+            };
+            let IDLValue::Float64(v2) = cast_type(v2.clone(), &TypeInner::Float64.into())? else {
+                panic!()
+            };
+            match op {
+                "add" => IDLValue::Float64(v1 + v2),
+                "sub" => IDLValue::Float64(v1 - v2),
+                "mul" => IDLValue::Float64(v1 * v2),
+                "div" => IDLValue::Float64(v1 / v2),
+                "lt" => IDLValue::Bool(v1 < v2),
+                "lte" => IDLValue::Bool(v1 <= v2),
+                "gt" => IDLValue::Bool(v1 > v2),
+                "gte" => IDLValue::Bool(v1 >= v2),
+                _ => unreachable!(),
+            }
+        }
+        (v1, v2) => {
+            let IDLValue::Int(v1) = cast_type(v1.clone(), &TypeInner::Int.into())? else {
+                panic!()
+            };
+            let IDLValue::Int(v2) = cast_type(v2.clone(), &TypeInner::Int.into())? else {
+                panic!()
+            };
+            match op {
+                "add" => IDLValue::Number((v1 + v2).to_string()),
+                "sub" => IDLValue::Number((v1 - v2).to_string()),
+                "mul" => IDLValue::Number((v1 * v2).to_string()),
+                "div" => IDLValue::Number((v1 / v2).to_string()),
+                "lt" => IDLValue::Bool(v1 < v2),
+                "lte" => IDLValue::Bool(v1 <= v2),
+                "gt" => IDLValue::Bool(v1 > v2),
+                "gte" => IDLValue::Bool(v1 >= v2),
+                _ => unreachable!(),
+            }
+        }
+    })
+}
+
+/// Compare two scalar values. Numbers are compared by value regardless of their types.
+pub fn compare(v1: &IDLValue, v2: &IDLValue) -> Result<std::cmp::Ordering> {
+    Ok(match (v1, v2) {
+        (IDLValue::Text(s1), IDLValue::Text(s2)) => s1.cmp(s2),
+        (IDLValue::Bool(b1), IDLValue::Bool(b2)) => b1.cmp(b2),
+        (IDLValue::Principal(p1), IDLValue::Principal(p2)) => p1.cmp(p2),
+        (IDLValue::Float32(_) | IDLValue::Float64(_), _)
+        | (_, IDLValue::Float32(_) | IDLValue::Float64(_)) => {
+            let ty = TypeInner::Float64.into();
+            match (cast_type(v1.clone(), &ty), cast_type(v2.clone(), &ty)) {
+                (Ok(IDLValue::Float64(f1)), Ok(IDLValue::Float64(f2))) => f1
+                    .partial_cmp(&f2)
+                    .ok_or_else(|| anyhow!("cannot compare {v1} with {v2}"))?,
+                _ => return Err(anyhow!("cannot compare {v1} with {v2}")),
+            }
+        }
+        _ => {
+            let ty = TypeInner::Int.into();
+            match (cast_type(v1.clone(), &ty), cast_type(v2.clone(), &ty)) {
+                (Ok(IDLValue::Int(n1)), Ok(IDLValue::Int(n2))) => n1.cmp(&n2),
+                _ => return Err(anyhow!("cannot compare {v1} with {v2}")),
+            }
+        }
+    })
+}
+
+/// Stable sort of values by the key extracted from each value.
+pub fn sort_by_key<F>(vs: Vec<IDLValue>, key: F) -> Result<Vec<IDLValue>>
+where
+    F: Fn(&IDLValue) -> Result<IDLValue>,
+{
+    let mut keyed = Vec::with_capacity(vs.len());
+    for v in vs.into_iter() {
+        keyed.push((key(&v)?, v));
+    }
+    let mut err = None;
+    keyed.sort_by(|(k1, _), (k2, _)| {
+        compare(k1, k2).unwrap_or_else(|e| {
+            err.get_or_insert(e);
+            std::cmp::Ordering::Equal
+        })
+    });
+    if let Some(e) = err {
+        return Err(e);
+    }
+    Ok(keyed.into_iter().map(|(_, v)| v).collect())
+}
+
+/// Check that the keys extracted from the values are in ascending order, reporting the first out-of-order pair.
+pub fn check_sorted<F>(vs: &[IDLValue], key: F) -> Result<()>
+where
+    F: Fn(&IDLValue) -> Result<IDLValue>,
+{
+    for (i, pair) in vs.windows(2).enumerate() {
+        let (k1, k2) = (key(&pair[0])?, key(&pair[1])?);
+        if compare(&k1, &k2)? == std::cmp::Ordering::Greater {
+            return Err(anyhow!(
+                "vec is not sorted: element {i} ({k1}) is greater than element {} ({k2})",
+                i + 1
+            ));
+        }
+    }
+    Ok(())
+}
+
 pub fn str_to_principal(id: &str, helper: &MyHelper) -> Result<Principal> {
     let try_id = Principal::from_text(id);
     Ok(match try_id {
@@ -208,6 +876,34 @@ pub fn as_u32(v: &IDLValue) -> Result<u32> {
     }
 }
 
+/// Take the elements (or bytes for blob) in `start..end` from a vec or blob value. Out of range
+/// indices are clamped to the length of the value, unless `strict` is set.
+pub fn slice_value(
+    v: &IDLValue,
+    start: usize,
+    end: Option<usize>,
+    strict: bool,
+) -> Result<IDLValue> {
+    let len = match v {
+        IDLValue::Vec(vs) => vs.len(),
+        IDLValue::Blob(b) => b.len(),
+        _ => return Err(anyhow!("{v} is not a vec or blob")),
+    };
+    let end = end.unwrap_or(len);
+    if strict && (start > end || end > len) {
+        return Err(anyhow!(
+            "range {start}..{end} is out of bounds for length {len}"
+        ));
+    }
+    let end = end.min(len);
+    let start = start.min(end);
+    Ok(match v {
+        IDLValue::Vec(vs) => IDLValue::Vec(vs[start..end].to_vec()),
+        IDLValue::Blob(b) => IDLValue::Blob(b[start..end].to_vec()),
+        _ => unreachable!(),
+    })
+}
+
 pub fn get_field<'a>(fs: &'a [IDLField], key: &'a str) -> Option<&'a IDLValue> {
     fs.iter()
         .find(|f| f.id == Label::Named(key.to_string()))
@@ -240,7 +936,8 @@ pub fn random_value(
     use rand::Rng;
     let mut rng = rand::thread_rng();
     let seed: Vec<_> = (0..2048).map(|_| rng.gen::<u8>()).collect();
-    let result = candid_parser::random::any(&seed, config, env, &[ty.clone()], &Some(scope))?;
+    let result =
+        candid_parser::random::any(&seed, config, env, std::slice::from_ref(ty), &Some(scope))?;
     Ok(result.args[0].to_string())
 }
 
@@ -253,6 +950,72 @@ pub fn resolve_path(base: &Path, file: &str) -> PathBuf {
     }
 }
 
+/// List the files matching `pattern`, sorted by path. Wildcards `*` and `?` are only supported
+/// in the file name, e.g., `identities/*.pem`.
+pub fn glob_files(base: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = resolve_path(base, pattern);
+    let dir = pattern.parent().unwrap_or(base);
+    let name = pattern
+        .file_name()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow!("Invalid file pattern {pattern:?}"))?;
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Cannot read {dir:?}"))? {
+        let path = entry?.path();
+        let matched = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .is_some_and(|file| wildcard_match(name.as_bytes(), file.as_bytes()));
+        if matched && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn wildcard_match(pattern: &[u8], s: &[u8]) -> bool {
+    match (pattern.split_first(), s.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            wildcard_match(rest, s) || (!s.is_empty() && wildcard_match(pattern, &s[1..]))
+        }
+        (Some((b'?', rest)), Some((_, s))) => wildcard_match(rest, s),
+        (Some((p, rest)), Some((c, s))) if p == c => wildcard_match(rest, s),
+        _ => false,
+    }
+}
+
+/// The principal that Internet Identity presents for `anchor` to the frontend `origin`.
+/// The seed is `sha256(len(salt) ++ salt ++ len(anchor) ++ anchor ++ len(origin) ++ origin)`, where
+/// `anchor` is in decimal and each length is a single byte. The principal is the self-authenticating
+/// id of the DER-encoded canister signature public key `len(ii) ++ ii ++ seed`.
+pub fn ii_principal(ii: &Principal, salt: &[u8], anchor: u64, origin: &str) -> Result<Principal> {
+    use sha2::{Digest, Sha256};
+    let anchor = anchor.to_string();
+    let mut hasher = Sha256::new();
+    for part in [salt, anchor.as_bytes(), origin.as_bytes()] {
+        let len = u8::try_from(part.len()).map_err(|_| anyhow!("{part:?} is too long"))?;
+        hasher.update([len]);
+        hasher.update(part);
+    }
+    let seed = hasher.finalize();
+    let id = ii.as_slice();
+    let mut raw_key = vec![id.len() as u8];
+    raw_key.extend_from_slice(id);
+    raw_key.extend_from_slice(&seed);
+    // SEQUENCE { SEQUENCE { OID 1.3.6.1.4.1.56387.1.2 }, BIT STRING raw_key }
+    const OID: [u8; 12] = [
+        0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb8, 0x43, 0x01, 0x02,
+    ];
+    let mut der = vec![0x30, 0, 0x30, OID.len() as u8];
+    der.extend_from_slice(&OID);
+    der.extend_from_slice(&[0x03, raw_key.len() as u8 + 1, 0x00]);
+    der.extend_from_slice(&raw_key);
+    der[1] = (der.len() - 2) as u8;
+    Ok(Principal::self_authenticating(der))
+}
+
 pub fn get_dfx_hsm_pin() -> Result<String, String> {
     std::env::var("DFX_HSM_PIN").or_else(|_| {
         rpassword::prompt_password("HSM PIN: ")
@@ -261,6 +1024,77 @@ pub fn get_dfx_hsm_pin() -> Result<String, String> {
     })
 }
 
+#[derive(serde::Deserialize)]
+struct JsonDelegationChain {
+    delegations: Vec<JsonSignedDelegation>,
+    #[serde(rename = "publicKey")]
+    public_key: String,
+}
+#[derive(serde::Deserialize)]
+struct JsonSignedDelegation {
+    delegation: JsonDelegation,
+    signature: String,
+}
+#[derive(serde::Deserialize)]
+struct JsonDelegation {
+    expiration: String,
+    pubkey: String,
+    targets: Option<Vec<String>>,
+}
+
+/// Load a delegation chain in the JSON format produced by agent-js `DelegationChain.toJSON()`,
+/// where all bytes are hex-encoded and the expiration is a hex-encoded nanosecond timestamp.
+/// Returns the public key the chain starts from, and the signed delegations.
+pub fn load_delegation_chain(path: &Path) -> Result<(Vec<u8>, Vec<SignedDelegation>)> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Cannot read {path:?}"))?;
+    let chain = serde_json::from_str::<JsonDelegationChain>(&json)
+        .with_context(|| format!("{path:?} is not a valid delegation chain"))?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_nanos() as u64;
+    let mut targets: Option<Vec<Principal>> = None;
+    let mut delegations = Vec::with_capacity(chain.delegations.len());
+    for d in chain.delegations.into_iter() {
+        let expiration = u64::from_str_radix(&d.delegation.expiration, 16)
+            .context("delegation expiration is not a hex timestamp")?;
+        if expiration <= now {
+            return Err(anyhow!("delegation expired at {expiration} ns"));
+        }
+        let delegation_targets = match d.delegation.targets {
+            None => None,
+            Some(ids) => {
+                let mut res = Vec::with_capacity(ids.len());
+                for id in ids.iter() {
+                    res.push(Principal::try_from_slice(&hex::decode(id)?)?);
+                }
+                Some(res)
+            }
+        };
+        // The chain is only valid for canisters that appear in every delegation's targets
+        targets = match (targets, &delegation_targets) {
+            (None, t) => t.clone(),
+            (Some(t), None) => Some(t),
+            (Some(t), Some(new)) => Some(t.into_iter().filter(|id| new.contains(id)).collect()),
+        };
+        delegations.push(SignedDelegation {
+            delegation: Delegation {
+                pubkey: hex::decode(&d.delegation.pubkey)?,
+                expiration,
+                targets: delegation_targets,
+            },
+            signature: hex::decode(&d.signature)?,
+        });
+    }
+    if let Some(targets) = targets {
+        if targets.is_empty() {
+            return Err(anyhow!("delegation chain has no common target canister"));
+        }
+        let targets: Vec<_> = targets.iter().map(|id| id.to_text()).collect();
+        eprintln!("Delegation is restricted to {}", targets.join(", "));
+    }
+    Ok((hex::decode(&chain.public_key)?, delegations))
+}
+
 #[tokio::main]
 pub async fn fetch_state_path(agent: &Agent, mut path: StatePath) -> anyhow::Result<IDLValue> {
     if path.effective_id.is_none() {
@@ -301,6 +1135,105 @@ pub async fn fetch_metadata(
         _ => unreachable!(),
     }
 }
+/// Read a custom metadata section of the canister. Returns text if the section is valid utf8, otherwise blob.
+#[tokio::main]
+pub async fn fetch_metadata_section(
+    agent: &Agent,
+    id: Principal,
+    name: &str,
+) -> anyhow::Result<IDLValue> {
+    use ic_agent::AgentError;
+    match fetch_metadata(agent, id, &format!("metadata/{name}")).await {
+        Ok(bytes) => Ok(match String::from_utf8(bytes) {
+            Ok(text) => IDLValue::Text(text),
+            Err(e) => IDLValue::Blob(e.into_bytes()),
+        }),
+        Err(e) => match e.downcast_ref::<AgentError>() {
+            Some(AgentError::LookupPathAbsent(_)) => {
+                Err(anyhow!("canister {id} has no metadata section {name}"))
+            }
+            Some(AgentError::HttpError(payload)) if payload.status == 403 => Err(anyhow!(
+                "metadata section {name} of canister {id} is private, only controllers can read it"
+            )),
+            _ => Err(e),
+        },
+    }
+}
+/// Find the subnet of the canister from the delegation in a read_state certificate. A certificate without
+/// delegation is signed by the root subnet, whose id is only known on mainnet.
+#[tokio::main]
+pub async fn fetch_subnet_id(
+    agent: &Agent,
+    id: Principal,
+    is_mainnet: bool,
+) -> anyhow::Result<Option<Principal>> {
+    let cert = agent.read_state_raw(vec![vec!["time".into()]], id).await?;
+    Ok(match cert.delegation {
+        Some(delegation) => Some(Principal::from_slice(&delegation.subnet_id)),
+        None if is_mainnet => Some(Principal::from_text(
+            "tdb26-jop6k-aogll-7ltgs-eruif-6kk7m-qpktf-gdiqx-mxtrf-vb5e6-eqe",
+        )?),
+        None => None,
+    })
+}
+#[derive(candid::CandidType)]
+struct FetchCanisterLogsArgs {
+    canister_id: Principal,
+}
+#[derive(candid::CandidType, candid::Deserialize)]
+struct FetchCanisterLogsResult {
+    canister_log_records: Vec<CanisterLogRecord>,
+}
+#[derive(candid::CandidType, candid::Deserialize)]
+pub struct CanisterLogRecord {
+    pub idx: u64,
+    pub timestamp_nanos: u64,
+    pub content: Vec<u8>,
+}
+/// Fetch the recent log entries of the canister from the management canister.
+#[tokio::main]
+pub async fn fetch_canister_logs(
+    agent: &Agent,
+    id: Principal,
+) -> anyhow::Result<Vec<CanisterLogRecord>> {
+    use candid::{Decode, Encode};
+    let bytes = agent
+        .query(&Principal::management_canister(), "fetch_canister_logs")
+        .with_arg(Encode!(&FetchCanisterLogsArgs { canister_id: id })?)
+        .with_effective_canister_id(id)
+        .call()
+        .await
+        .with_context(|| format!("Cannot fetch the logs of canister {id}. Logs are only visible to the controllers, unless the log_visibility of the canister is public"))?;
+    Ok(Decode!(&bytes, FetchCanisterLogsResult)?.canister_log_records)
+}
+/// Current time in nanoseconds since the Unix epoch.
+pub fn now_nanos() -> Result<u64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_nanos() as u64)
+}
+/// Format nanoseconds since the Unix epoch as an RFC 3339 UTC timestamp with millisecond precision.
+pub fn format_timestamp(nanos: u64) -> String {
+    let secs = nanos / 1_000_000_000;
+    let millis = nanos % 1_000_000_000 / 1_000_000;
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Convert days since epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{millis:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
 async fn get_canister_id_from_subnet(
     agent: &Agent,
     subnet_id: ic_agent::hash_tree::Label<Vec<u8>>,